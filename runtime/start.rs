@@ -7,6 +7,20 @@ extern "C" {
     fn our_code_starts_here() -> i64;
 }
 
+// Error codes match runtime_error_code in src/main.rs. The generated code
+// also passes an error-site id in the second argument; only the JIT's
+// snek_error has the source to look it up in, so it's ignored here.
+#[export_name = "\x01snek_error"]
+pub extern "C" fn snek_error(errcode: i64) {
+  let msg = match errcode {
+    1 => "divide by zero",
+    2 => "overflow",
+    _ => "unknown error",
+  };
+  eprintln!("an error occurred: {msg}");
+  std::process::exit(1); // EXIT_RUNTIME_ERROR in src/main.rs
+}
+
 fn main() {
   let i : i64 = unsafe {
     our_code_starts_here()
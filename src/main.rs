@@ -1,200 +1,2594 @@
-use dynasmrt::{dynasm, DynasmApi};
+use dynasmrt::{dynasm, DynamicLabel, DynasmApi, DynasmLabelApi};
 
+use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::IsTerminal;
 use std::mem;
+use std::process::Command;
+use std::hint::black_box;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use sexp::Atom::*;
 use sexp::*;
 
+#[derive(Debug)]
 enum Val {
     Reg(Reg),
+    Reg32(Reg),
     Imm(i32),
+    RegOffset(Reg, i32),
 }
 
 use Val::*;
 
+#[derive(Debug, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 enum Reg {
     RAX,
+    RCX,
+    RDX,
+    RSP,
+    RSI,
+    RDI,
 }
 
-use Reg::*;
+use crate::Reg::*;
 
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 enum Instr {
     IMov(Val, Val),
+    IMovsxd(Val, Val),
     IAdd(Val, Val),
     ISub(Val, Val),
+    INeg(Val),
+    ICmp(Val, Val),
+    ICmovg(Val, Val),
+    ICmovl(Val, Val),
+    ICdq,
+    IIDiv(Val),
+    ILabel(String),
+    IJmp(String),
+    IJe(String),
+    IJne(String),
+    ICall(String),
+    IRet,
 }
 
-enum Expr {
+// A byte range into the program source.
+type Span = std::ops::Range<usize>;
+
+// Every node remembers the source text it was parsed from, so diagnostics can
+// point back at it; codegen and interp only look at `kind`.
+#[derive(Debug)]
+struct Expr {
+    kind: ExprKind,
+    span: Span,
+}
+
+#[derive(Debug)]
+enum ExprKind {
     Num(i32),
     Add1(Box<Expr>),
     Sub1(Box<Expr>),
+    Negate(Box<Expr>),
+    Mod(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+    Plus(Box<Expr>, Box<Expr>),
+    Min(Box<Expr>, Box<Expr>),
+    Max(Box<Expr>, Box<Expr>),
 }
 
-fn parse_expr(s: &Sexp) -> Expr {
-    match s {
-        Sexp::Atom(I(n)) => Expr::Num(i32::try_from(*n).unwrap()),
-        Sexp::List(vec) => match &vec[..] {
-            [Sexp::Atom(S(op)), e] if op == "add1" => Expr::Add1(Box::new(parse_expr(e))),
-            [Sexp::Atom(S(op)), e] if op == "sub1" => Expr::Sub1(Box::new(parse_expr(e))),
-            _ => panic!("parse error"),
+// Errors the generated code reports through snek_error. The codes are shared
+// with runtime/start.rs, so keep the two in sync.
+#[derive(Debug)]
+enum RuntimeError {
+    DivideByZero,
+    Overflow,
+}
+
+fn runtime_error_code(e: &RuntimeError) -> i32 {
+    match e {
+        RuntimeError::DivideByZero => 1,
+        RuntimeError::Overflow => 2,
+    }
+}
+
+fn runtime_error_message(code: i64) -> &'static str {
+    match code {
+        1 => "divide by zero",
+        2 => "overflow",
+        _ => "unknown error",
+    }
+}
+
+// Process exit codes, one per class of error. runtime/start.rs also exits
+// with EXIT_RUNTIME_ERROR from its snek_error. EXIT_VERIFY_FAILED means the
+// program is fine but the two backends disagree about it.
+const EXIT_RUNTIME_ERROR: i32 = 1;
+const EXIT_PARSE_ERROR: i32 = 2;
+const EXIT_COMPILE_ERROR: i32 = 3;
+const EXIT_USAGE_ERROR: i32 = 4;
+const EXIT_VERIFY_FAILED: i32 = 5;
+
+// "an error occurred: <message>", then the failing expression in the source
+// when it's known.
+fn runtime_error_report(message: &str, excerpt: Option<String>) -> String {
+    format!("an error occurred: {message}\n{}", excerpt.unwrap_or_default())
+}
+
+fn report_runtime_error(message: &str, excerpt: Option<String>) -> ! {
+    eprint!("{}", runtime_error_report(message, excerpt));
+    std::process::exit(EXIT_RUNTIME_ERROR);
+}
+
+fn report_interp_error(source: &str, color: bool, e: &InterpError, span: &Span) -> ! {
+    let excerpt = source_excerpt(source, 1, span.start, color);
+    report_runtime_error(&interp_error_message(e), Some(excerpt))
+}
+
+// What snek_error needs to point at the expression that failed: each check
+// loads an error-site id into RSI, which indexes `spans` (one per Expr node,
+// numbered as in Origins). snek_error has no other way to reach it, so run()
+// fills it in before calling the JITted code.
+struct ErrorSites {
+    source: String,
+    spans: Vec<Span>,
+    color: bool,
+}
+
+static ERROR_SITES: Mutex<Option<ErrorSites>> = Mutex::new(None);
+
+extern "C" fn snek_error(errcode: i64, site: i64) -> ! {
+    let excerpt = ERROR_SITES.lock().ok().and_then(|sites| {
+        let sites = sites.as_ref()?;
+        let span = sites.spans.get(usize::try_from(site).ok()?)?;
+        Some(source_excerpt(&sites.source, 1, span.start, sites.color))
+    });
+    report_runtime_error(runtime_error_message(errcode), excerpt)
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{message}");
+    std::process::exit(EXIT_USAGE_ERROR);
+}
+
+#[derive(Debug)]
+enum ParseError {
+    // The text isn't a well-formed s-expression at all; the rest are about
+    // an s-expression that doesn't describe a valid program.
+    MalformedSexp(String),
+    EmptyExpression,
+    NumberOutOfRange(i64),
+    UnknownChar(String),
+    WrongArity {
+        op: String,
+        expected: usize,
+        got: usize,
+    },
+    TooFewArguments {
+        op: String,
+        got: usize,
+    },
+    UnboundIdentifier(String),
+    Invalid(String),
+}
+
+fn parse_error_message(e: &ParseError) -> String {
+    match e {
+        ParseError::MalformedSexp(message) => format!("malformed s-expression: {message}"),
+        ParseError::EmptyExpression => String::from("empty expression: () is not a valid expression"),
+        ParseError::NumberOutOfRange(n) => format!("number out of range: {n} does not fit in 32 bits"),
+        ParseError::UnknownChar(c) => format!("unknown character literal #\\{c}"),
+        ParseError::WrongArity { op, expected, got } => {
+            format!("wrong number of arguments: {op} expects {expected}, got {got}")
+        }
+        ParseError::TooFewArguments { op, got } => {
+            format!("wrong number of arguments: {op} expects at least 2, got {got}")
+        }
+        // There are no bindings (or true/false/input) yet, so every name is
+        // unbound; operator names get a hint since that's the likely slip.
+        ParseError::UnboundIdentifier(name) if is_reserved(name) => {
+            format!("unbound identifier {name}: did you mean ({name} ...)?")
+        }
+        ParseError::UnboundIdentifier(name) => format!("unbound identifier {name}"),
+        ParseError::Invalid(s) => format!("parse error: invalid expression {s}"),
+    }
+}
+
+// The program is read fine but names something that doesn't exist or calls
+// an operator with the wrong number of arguments: a compile error, not a
+// parse error, even though parse_expr is where it's found.
+fn parse_error_exit_code(e: &ParseError) -> i32 {
+    match e {
+        ParseError::WrongArity { .. }
+        | ParseError::TooFewArguments { .. }
+        | ParseError::UnboundIdentifier(_) => EXIT_COMPILE_ERROR,
+        _ => EXIT_PARSE_ERROR,
+    }
+}
+
+// Formats an error like "error: <message>", followed by the offending source
+// line and a caret under `index` (a byte offset into `source`) when known.
+// `source` starts on line `first_line` of its file.
+fn render_error(source: &str, first_line: usize, message: &str, index: Option<usize>, color: bool) -> String {
+    let (red, bold, reset) = if color {
+        ("\x1b[31;1m", "\x1b[1m", "\x1b[0m")
+    } else {
+        ("", "", "")
+    };
+    let mut out = format!("{red}error{reset}{bold}: {message}{reset}\n");
+    if let Some(index) = index {
+        out.push_str(&source_excerpt(source, first_line, index, color));
+    }
+    out
+}
+
+// The source line containing `index`, with a caret under it, numbered as if
+// `source` started on line `first_line`.
+fn source_excerpt(source: &str, first_line: usize, index: usize, color: bool) -> String {
+    let (red, reset) = if color { ("\x1b[31;1m", "\x1b[0m") } else { ("", "") };
+    // An eof error points just past the last non-blank character.
+    let index = index.min(source.trim_end().len());
+    let line_start = source[..index].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[index..].find('\n').map_or(source.len(), |i| index + i);
+    let line_no = source[..line_start].matches('\n').count() + first_line;
+    let column = source[line_start..index].chars().count();
+    let gutter = " ".repeat(line_no.to_string().len());
+    format!(
+        "{line_no} | {}\n{gutter} | {}{red}^{reset}\n",
+        &source[line_start..line_end],
+        " ".repeat(column)
+    )
+}
+
+fn operator_arity(op: &str) -> Option<usize> {
+    match op {
+        "add1" | "sub1" | "negate" | "id" | "begin" => Some(1),
+        "mod" | "/" => Some(2),
+        _ => None,
+    }
+}
+
+// (+ a b c ...), (min ...) and (max ...) take two or more operands and are
+// folded from the left into the binary node this returns a constructor for.
+type BinaryExpr = fn(Box<Expr>, Box<Expr>) -> ExprKind;
+
+fn variadic_op(op: &str) -> Option<BinaryExpr> {
+    match op {
+        "+" => Some(ExprKind::Plus),
+        "min" => Some(ExprKind::Min),
+        "max" => Some(ExprKind::Max),
+        _ => None,
+    }
+}
+
+// Reserved words are exactly the operator names, so they're read off the two
+// operator tables rather than kept in a list of their own that could drift.
+fn is_reserved(name: &str) -> bool {
+    operator_arity(name).is_some() || variadic_op(name).is_some()
+}
+
+// Character literals are written #\X and read as their code point.
+fn parse_char(name: &str) -> Result<i32, ParseError> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c as i32),
+        _ => match name {
+            "newline" => Ok('\n' as i32),
+            "space" => Ok(' ' as i32),
+            "tab" => Ok('\t' as i32),
+            _ => Err(ParseError::UnknownChar(name.to_string())),
         },
-        _ => panic!("parse error"),
     }
 }
 
+// Where each s-expression sits in the source, in the same shape as the Sexp
+// sexp::parse returned for it (the sexp crate doesn't keep positions). This
+// is only run on text sexp has already accepted, so it follows sexp's
+// tokenizing rules without having to report errors of its own.
+enum SexpSpans {
+    Atom(Span),
+    List(Span, Vec<SexpSpans>),
+}
+
+impl SexpSpans {
+    fn span(&self) -> Span {
+        match self {
+            SexpSpans::Atom(span) | SexpSpans::List(span, _) => span.clone(),
+        }
+    }
+
+    fn items(&self) -> &[SexpSpans] {
+        match self {
+            SexpSpans::Atom(_) => &[],
+            SexpSpans::List(_, items) => items,
+        }
+    }
+}
+
+// Whitespace and ; comments, as sexp skips them.
+fn skip_space(source: &str, pos: &mut usize) {
+    while let Some(c) = source[*pos..].chars().next() {
+        if c == ';' {
+            *pos = source[*pos..].find('\n').map_or(source.len(), |i| *pos + i + 1);
+        } else if c.is_whitespace() {
+            *pos += c.len_utf8();
+        } else {
+            break;
+        }
+    }
+}
+
+fn scan_spans(source: &str, pos: &mut usize) -> SexpSpans {
+    skip_space(source, pos);
+    let start = *pos;
+    let rest = &source[start..];
+    if rest.starts_with('(') {
+        *pos += 1;
+        let mut items = Vec::new();
+        loop {
+            skip_space(source, pos);
+            if *pos == source.len() || source[*pos..].starts_with(')') {
+                break;
+            }
+            items.push(scan_spans(source, pos));
+        }
+        *pos = (*pos + 1).min(source.len());
+        SexpSpans::List(start..*pos, items)
+    } else if let Some(quoted) = rest.strip_prefix('"') {
+        // A backslash escapes whatever follows it, quotes included.
+        let mut escaped = false;
+        let len = quoted
+            .char_indices()
+            .find(|&(_, c)| {
+                let closes = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                closes
+            })
+            .map_or(rest.len(), |(i, _)| i + 2);
+        *pos += len;
+        SexpSpans::Atom(start..*pos)
+    } else {
+        let len = rest
+            .find(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == ';')
+            .unwrap_or(rest.len());
+        *pos += len;
+        SexpSpans::Atom(start..*pos)
+    }
+}
+
+// Errors carry the span of the form they're about.
+fn parse_expr(s: &Sexp, spans: &SexpSpans) -> Result<Expr, (ParseError, Span)> {
+    let span = spans.span();
+    let at = |e: ParseError| (e, span.clone());
+    let node = |kind: ExprKind| Expr { kind, span: span.clone() };
+    match s {
+        Sexp::Atom(I(n)) => i32::try_from(*n)
+            .map(|n| node(ExprKind::Num(n)))
+            .map_err(|_| at(ParseError::NumberOutOfRange(*n))),
+        Sexp::Atom(S(name)) if name.starts_with("#\\") => {
+            Ok(node(ExprKind::Num(parse_char(&name[2..]).map_err(at)?)))
+        }
+        Sexp::Atom(S(name)) => Err(at(ParseError::UnboundIdentifier(name.clone()))),
+        Sexp::List(vec) => {
+            let operand = |i: usize| parse_expr(&vec[i], &spans.items()[i]).map(Box::new);
+            match &vec[..] {
+                [] => Err(at(ParseError::EmptyExpression)),
+                // Redundant parentheses, as in (5) or ((add1 5)), are allowed;
+                // (add1) is still an operator missing its operand.
+                [e] if !matches!(e, Sexp::Atom(S(op)) if is_reserved(op)) => operand(0).map(|e| *e),
+                // id and begin just evaluate their operand, so they leave no
+                // trace in the Expr.
+                [Sexp::Atom(S(op)), _] if op == "id" || op == "begin" => operand(1).map(|e| *e),
+                [Sexp::Atom(S(op)), _] if op == "add1" => Ok(node(ExprKind::Add1(operand(1)?))),
+                [Sexp::Atom(S(op)), _] if op == "sub1" => Ok(node(ExprKind::Sub1(operand(1)?))),
+                [Sexp::Atom(S(op)), _] if op == "negate" => Ok(node(ExprKind::Negate(operand(1)?))),
+                [Sexp::Atom(S(op)), _, _] if op == "mod" => {
+                    Ok(node(ExprKind::Mod(operand(1)?, operand(2)?)))
+                }
+                [Sexp::Atom(S(op)), _, _] if op == "/" => {
+                    Ok(node(ExprKind::Div(operand(1)?, operand(2)?)))
+                }
+                // Every node of the fold gets the whole form's span.
+                [Sexp::Atom(S(op)), _, _, ..] if variadic_op(op).is_some() => {
+                    let make = variadic_op(op).unwrap();
+                    (2..vec.len()).try_fold(*operand(1)?, |acc, i| {
+                        Ok(node(make(Box::new(acc), operand(i)?)))
+                    })
+                }
+                [Sexp::Atom(S(op)), args @ ..] if variadic_op(op).is_some() => {
+                    Err(at(ParseError::TooFewArguments { op: op.clone(), got: args.len() }))
+                }
+                [Sexp::Atom(S(op)), args @ ..] if operator_arity(op).is_some() => {
+                    Err(at(ParseError::WrongArity {
+                        op: op.clone(),
+                        expected: operator_arity(op).unwrap(),
+                        got: args.len(),
+                    }))
+                }
+                _ => Err(at(ParseError::Invalid(s.to_string()))),
+            }
+        }
+        _ => Err(at(ParseError::Invalid(s.to_string()))),
+    }
+}
+
+// The one place a register's names and dynasm encoding are written down, so
+// the text assembly and the JITted bytes can't disagree: (64-bit name, 32-bit
+// name, index). The match is exhaustive, so a new Reg can't be missed.
+fn reg_info(r: &Reg) -> (&'static str, &'static str, u8) {
+    match r {
+        RAX => ("RAX", "EAX", 0),
+        RCX => ("RCX", "ECX", 1),
+        RDX => ("RDX", "EDX", 2),
+        RSP => ("RSP", "ESP", 4),
+        RSI => ("RSI", "ESI", 6),
+        RDI => ("RDI", "EDI", 7),
+    }
+}
+
+fn reg_to_str(r: &Reg) -> &'static str {
+    reg_info(r).0
+}
+
+fn reg32_to_str(r: &Reg) -> &'static str {
+    reg_info(r).1
+}
+
 fn val_to_str(v: &Val) -> String {
     match v {
-        Reg(RAX) => String::from("RAX"),
+        Reg(r) => String::from(reg_to_str(r)),
+        Reg32(r) => String::from(reg32_to_str(r)),
         Imm(n) => format!("DWORD {n}"),
+        // Slots inside an allocated frame sit above RSP.
+        RegOffset(r, n) if *n < 0 => format!("QWORD [{} + {}]", reg_to_str(r), -n),
+        RegOffset(r, n) => format!("QWORD [{} - {n}]", reg_to_str(r)),
     }
 }
 
 fn reg_to_index(r: &Reg) -> u8 {
-    match r {
-        RAX => 0,
+    reg_info(r).2
+}
+
+// An immediate takes its size from the operand it's paired with: a register
+// implies one and a memory operand is already written QWORD, so the keyword
+// is dropped. (Either way the encoding holds a sign-extended 32-bit
+// immediate; a QWORD immediate would ask NASM for a 64-bit one.)
+fn operands_to_str(v1: &Val, v2: &Val) -> String {
+    match (v1, v2) {
+        (_, Imm(n)) => format!("{}, {n}", val_to_str(v1)),
+        _ => format!("{}, {}", val_to_str(v1), val_to_str(v2)),
     }
 }
 
 fn instr_to_str(i: &Instr) -> String {
     match i {
         Instr::IMov(v1, v2) => {
-            return format!("mov {}, {}", val_to_str(&v1), val_to_str(&v2));
+            format!("mov {}", operands_to_str(v1, v2))
+        }
+        Instr::IMovsxd(v1, v2) => {
+            format!("movsxd {}", operands_to_str(v1, v2))
         }
         Instr::ISub(v1, v2) => {
-            return format!("sub {}, {}", val_to_str(&v1), val_to_str(&v2));
+            format!("sub {}", operands_to_str(v1, v2))
         }
         Instr::IAdd(v1, v2) => {
-            return format!("add {}, {}", val_to_str(&v1), val_to_str(&v2));
+            format!("add {}", operands_to_str(v1, v2))
+        }
+        Instr::ICmp(v1, v2) => {
+            format!("cmp {}", operands_to_str(v1, v2))
         }
+        Instr::ICmovg(v1, v2) => {
+            format!("cmovg {}", operands_to_str(v1, v2))
+        }
+        Instr::ICmovl(v1, v2) => {
+            format!("cmovl {}", operands_to_str(v1, v2))
+        }
+        Instr::INeg(v) => format!("neg {}", val_to_str(v)),
+        Instr::ICdq => String::from("cdq"),
+        Instr::IIDiv(v) => format!("idiv {}", val_to_str(v)),
+        Instr::ILabel(l) => format!("{l}:"),
+        Instr::IJmp(l) => format!("jmp {l}"),
+        Instr::IJe(l) => format!("je {l}"),
+        Instr::IJne(l) => format!("jne {l}"),
+        Instr::ICall(f) => format!("call {f}{CALL_SUFFIX}"),
+        Instr::IRet => String::from("ret"),
     }
 }
 
-fn instrs_to_str(cmds: &Vec<Instr>) -> String {
+fn instrs_to_str(cmds: &[Instr]) -> String {
     cmds.iter()
-        .map(|c| instr_to_str(c))
+        .map(instr_to_str)
         .collect::<Vec<_>>()
         .join("\n")
 }
 
-fn instr_to_asm(i: &Instr, ops: &mut dynasmrt::x64::Assembler) {
+fn runtime_fn_addr(name: &str) -> i64 {
+    match name {
+        "snek_error" => snek_error as *const () as i64,
+        _ => panic!("Unknown runtime function {name}"),
+    }
+}
+
+fn instr_to_asm(
+    i: &Instr,
+    ops: &mut dynasmrt::x64::Assembler,
+    labels: &HashMap<String, DynamicLabel>,
+) {
     match i {
         Instr::IMov(Reg(r), Imm(n)) => {
             dynasm!(ops ; .arch x64 ; mov Rq(reg_to_index(r)), *n);
         }
+        Instr::IMov(Reg(r1), Reg(r2)) => {
+            dynasm!(ops ; .arch x64 ; mov Rq(reg_to_index(r1)), Rq(reg_to_index(r2)));
+        }
+        Instr::IMov(Reg(r1), RegOffset(r2, n)) => {
+            dynasm!(ops ; .arch x64 ; mov Rq(reg_to_index(r1)), QWORD [Rq(reg_to_index(r2)) - *n]);
+        }
+        Instr::IMov(RegOffset(r1, n), Reg(r2)) => {
+            dynasm!(ops ; .arch x64 ; mov QWORD [Rq(reg_to_index(r1)) - *n], Rq(reg_to_index(r2)));
+        }
+        Instr::IMov(Reg32(r1), Reg32(r2)) => {
+            dynasm!(ops ; .arch x64 ; mov Rd(reg_to_index(r1)), Rd(reg_to_index(r2)));
+        }
+        Instr::IMovsxd(Reg(r1), Reg32(r2)) => {
+            dynasm!(ops ; .arch x64 ; movsxd Rq(reg_to_index(r1)), Rd(reg_to_index(r2)));
+        }
         Instr::IAdd(Reg(r), Imm(n)) => {
             dynasm!(ops ; .arch x64 ; add Rq(reg_to_index(r)), *n);
         }
         Instr::ISub(Reg(r), Imm(n)) => {
             dynasm!(ops ; .arch x64 ; sub Rq(reg_to_index(r)), *n);
         }
+        Instr::INeg(Reg(r)) => {
+            dynasm!(ops ; .arch x64 ; neg Rq(reg_to_index(r)));
+        }
+        Instr::ICmp(Reg32(r), Imm(n)) => {
+            dynasm!(ops ; .arch x64 ; cmp Rd(reg_to_index(r)), *n);
+        }
+        Instr::IAdd(Reg(r1), Reg(r2)) => {
+            dynasm!(ops ; .arch x64 ; add Rq(reg_to_index(r1)), Rq(reg_to_index(r2)));
+        }
+        Instr::ICmp(Reg32(r1), Reg32(r2)) => {
+            dynasm!(ops ; .arch x64 ; cmp Rd(reg_to_index(r1)), Rd(reg_to_index(r2)));
+        }
+        Instr::ICmovg(Reg(r1), Reg(r2)) => {
+            dynasm!(ops ; .arch x64 ; cmovg Rq(reg_to_index(r1)), Rq(reg_to_index(r2)));
+        }
+        Instr::ICmovl(Reg(r1), Reg(r2)) => {
+            dynasm!(ops ; .arch x64 ; cmovl Rq(reg_to_index(r1)), Rq(reg_to_index(r2)));
+        }
+        Instr::ICdq => {
+            dynasm!(ops ; .arch x64 ; cdq);
+        }
+        Instr::IIDiv(Reg32(r)) => {
+            dynasm!(ops ; .arch x64 ; idiv Rd(reg_to_index(r)));
+        }
+        Instr::ILabel(l) => {
+            dynasm!(ops ; .arch x64 ; =>labels[l]);
+        }
+        Instr::IJmp(l) => {
+            dynasm!(ops ; .arch x64 ; jmp =>labels[l]);
+        }
+        Instr::IJe(l) => {
+            dynasm!(ops ; .arch x64 ; je =>labels[l]);
+        }
+        Instr::IJne(l) => {
+            dynasm!(ops ; .arch x64 ; jne =>labels[l]);
+        }
+        Instr::ICall(f) => {
+            dynasm!(ops ; .arch x64 ; mov rax, QWORD runtime_fn_addr(f) ; call rax);
+        }
+        Instr::IRet => {
+            dynasm!(ops ; .arch x64 ; ret);
+        }
         _ => {
             panic!("Unknown instruction format")
         }
     }
 }
 
-fn instrs_to_asm(cmds: &Vec<Instr>, ops: &mut dynasmrt::x64::Assembler) {
-    cmds.iter().for_each(|c| instr_to_asm(c, ops))
+fn instrs_to_asm(cmds: &[Instr], ops: &mut dynasmrt::x64::Assembler) {
+    let mut labels = HashMap::new();
+    for c in cmds {
+        if let Instr::ILabel(l) = c {
+            labels.insert(l.clone(), ops.new_dynamic_label());
+        }
+    }
+    cmds.iter().for_each(|c| instr_to_asm(c, ops, &labels))
 }
 
-fn compile_expr_instrs(e: &Expr, cmds: &mut Vec<Instr>) {
+// Stack slots are WORD_SIZE bytes below RSP; slot 1 is the first one in use.
+const WORD_SIZE: i32 = 8;
+
+fn slot_offset(index: i32) -> i32 {
+    index * WORD_SIZE
+}
+
+// Slots live in the red zone below RSP, with no prologue, as long as they fit
+// in it; past this many bytes a signal handler could overwrite them, so the
+// code allocates a frame instead (see with_frame).
+const RED_ZONE_SIZE: i32 = 128;
+
+// The bytes with_frame allocates, if any.
+fn frame_bytes(instrs: &[Instr]) -> i32 {
+    match instrs.first() {
+        Some(Instr::ISub(Reg(RSP), Imm(n))) => *n,
+        _ => 0,
+    }
+}
+
+// The deepest slot the code touches, in bytes below RSP on entry.
+fn peak_stack_bytes(instrs: &[Instr]) -> i32 {
+    let offset = |v: &Val| match v {
+        RegOffset(RSP, n) => *n,
+        _ => i32::MIN,
+    };
+    instrs
+        .iter()
+        .map(|i| match i {
+            Instr::IMov(v1, v2) => offset(v1).max(offset(v2)),
+            _ => i32::MIN,
+        })
+        .max()
+        .filter(|&n| n != i32::MIN)
+        .map_or(0, |n| n + frame_bytes(instrs))
+}
+
+// Moves RSP below the slots of `body` when they don't fit in the red zone,
+// rounding the frame up to 16 bytes so the stack stays aligned for
+// snek_error the same way it is without one. Slots are then addressed
+// relative to the lowered RSP. Returns the frame size, or 0 for none.
+fn with_frame(body: &mut Vec<Instr>, origins: &mut Vec<Option<usize>>) -> i32 {
+    let peak = peak_stack_bytes(body);
+    if peak <= RED_ZONE_SIZE {
+        return 0;
+    }
+    let frame = (peak + 15) / 16 * 16;
+    for i in body.iter_mut() {
+        if let Instr::IMov(v1, v2) = i {
+            for v in [v1, v2] {
+                if let RegOffset(RSP, n) = v {
+                    *n -= frame;
+                }
+            }
+        }
+    }
+    body.insert(0, Instr::ISub(Reg(RSP), Imm(frame)));
+    origins.insert(0, None);
+    frame
+}
+
+#[derive(Debug)]
+enum JitError {
+    NoExecutableMemory(String),
+}
+
+fn jit_error_message(e: &JitError) -> String {
     match e {
-        Expr::Num(n) => cmds.push(Instr::IMov(Reg(RAX), Imm(*n))),
-        Expr::Add1(subexpr) => {
-            compile_expr_instrs(&subexpr, cmds);
+        JitError::NoExecutableMemory(cause) => format!(
+            "could not allocate executable memory for JIT ({cause}); use --interp or --emit=asm instead"
+        ),
+    }
+}
+
+// Owns the assembled code, so the function pointer can't outlive its buffer:
+// call() only transmutes while holding the reader lock, for the duration of
+// the call, and alter() can't run while that lock is held.
+struct JitFunction {
+    ops: dynasmrt::x64::Assembler,
+    start: dynasmrt::AssemblyOffset,
+}
+
+impl JitFunction {
+    // Fails when the OS won't hand out executable memory (seccomp, hardened
+    // kernels), either for the buffer or for making it executable.
+    fn new(instrs: &[Instr]) -> Result<JitFunction, JitError> {
+        JitFunction::with_assembler(dynasmrt::x64::Assembler::new(), instrs)
+    }
+
+    // new() with the assembler already created (or not), so a failed
+    // allocation can be simulated.
+    fn with_assembler(
+        ops: std::io::Result<dynasmrt::x64::Assembler>,
+        instrs: &[Instr],
+    ) -> Result<JitFunction, JitError> {
+        let mut ops = ops.map_err(|e| JitError::NoExecutableMemory(e.to_string()))?;
+        let start = ops.offset();
+        instrs_to_asm(instrs, &mut ops);
+        ops.commit().map_err(|e| JitError::NoExecutableMemory(e.to_string()))?;
+        let jitted = JitFunction { ops, start };
+        jitted.check_w_xor_x();
+        Ok(jitted)
+    }
+
+    // commit() and alter() leave the buffer mapped read+execute; it should
+    // never be writable while it can run (W^X).
+    fn check_w_xor_x(&self) {
+        debug_assert!(
+            self.protection().is_none_or(|p| !(p.contains('w') && p.contains('x'))),
+            "JIT buffer is both writable and executable"
+        );
+    }
+
+    // The permissions of the mapping holding the code, as /proc/self/maps
+    // spells them (e.g. "r-xp"), or None where that isn't available.
+    fn protection(&self) -> Option<String> {
+        let addr = self.entry() as usize;
+        let maps = std::fs::read_to_string("/proc/self/maps").ok()?;
+        maps.lines().find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (lo, hi) = fields.next()?.split_once('-')?;
+            let (lo, hi) = (usize::from_str_radix(lo, 16).ok()?, usize::from_str_radix(hi, 16).ok()?);
+            (lo <= addr && addr < hi).then(|| fields.next().map(String::from))?
+        })
+    }
+
+    // Reads all of RAX; compile_to_instrs widens the 32-bit result before ret.
+    fn call(&self) -> i64 {
+        let reader = self.ops.reader();
+        let buf = reader.lock();
+        let f: extern "C" fn() -> i64 = unsafe { mem::transmute(buf.ptr(self.start)) };
+        f()
+    }
+
+    // Runs the code on its own thread and gives up waiting after `limit`.
+    // Native code can't be stopped from outside, so on a timeout the thread
+    // is leaked, still running and holding the reader lock; the caller is
+    // expected to exit rather than call or alter the function again.
+    fn call_with_timeout(&self, limit: Duration) -> Option<i64> {
+        let executor = self.ops.reader();
+        let start = self.start;
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let buf = executor.lock();
+            let f: extern "C" fn() -> i64 = unsafe { mem::transmute(buf.ptr(start)) };
+            let _ = tx.send(f());
+        });
+        rx.recv_timeout(limit).ok()
+    }
+
+    // Only for display; the pointer is not meant to be called.
+    fn entry(&self) -> *const u8 {
+        self.ops.reader().lock().ptr(self.start)
+    }
+
+    // Rewrites the code in place, starting from the entry point.
+    fn alter(&mut self, f: impl FnOnce(&mut dynasmrt::Modifier<dynasmrt::x64::X64Relocation>)) {
+        self.ops.alter(f).unwrap();
+        self.ops.commit().unwrap(); // is this necessary? probably
+        // So, you could just call the old function pointer again (it “works”,
+        // but probably not always). I think this is safer (?) because the
+        // reader() is designed to make sure everything is finalized and read
+        // only before jumping and executing, which is why call() goes through
+        // it every time. Hard to test the failure case.
+        self.check_w_xor_x();
+    }
+}
+
+fn new_label(l: &mut i32, s: &str) -> String {
+    let current = *l;
+    *l += 1;
+    format!("{s}_{current}")
+}
+
+// Which Expr node emitted each instruction, for --explain. `of_instr` runs
+// parallel to the instruction list and indexes into `nodes`; instructions no
+// node owns (the final ret, the error handlers) are None.
+#[derive(Default)]
+struct Origins<'a> {
+    nodes: Vec<(&'a Expr, usize)>,
+    of_instr: Vec<Option<usize>>,
+    depth: usize,
+}
+
+// Leaves the quotient (for a Div node `e`) or the remainder (for a Mod) in
+// RAX. idiv faults on a zero divisor and on i32::MIN / -1, so a zero divisor
+// is turned into a snek_error call first, with `site` (the node's id) in RSI
+// to say where. A divisor of -1 never reaches idiv: the quotient overflows
+// only for i32::MIN, and the remainder is always 0.
+fn compile_idiv_instrs<'a>(
+    site: usize,
+    e: &'a Expr,
+    si: i32,
+    l: &mut i32,
+    cmds: &mut Vec<Instr>,
+    origins: &mut Origins<'a>,
+) {
+    let (e1, e2, remainder) = match &e.kind {
+        ExprKind::Div(e1, e2) => (e1, e2, false),
+        ExprKind::Mod(e1, e2) => (e1, e2, true),
+        _ => panic!("compile_idiv_instrs on {e:?}"),
+    };
+    let divide = new_label(l, "divide");
+    // e1 first, as interp does, so the same error wins when both operands fail.
+    compile_expr_instrs(e1, si, l, cmds, origins);
+    cmds.push(Instr::IMov(RegOffset(RSP, slot_offset(si)), Reg(RAX)));
+    compile_expr_instrs(e2, si + 1, l, cmds, origins);
+    cmds.push(Instr::IMov(Reg(RCX), Reg(RAX)));
+    cmds.push(Instr::IMov(Reg(RAX), RegOffset(RSP, slot_offset(si))));
+    cmds.push(Instr::IMov(Reg(RSI), Imm(site as i32)));
+    cmds.push(Instr::ICmp(Reg32(RCX), Imm(0)));
+    cmds.push(Instr::IJe(String::from("divide_by_zero")));
+    cmds.push(Instr::ICmp(Reg32(RCX), Imm(-1)));
+    cmds.push(Instr::IJne(divide.clone()));
+    if remainder {
+        let done = new_label(l, "divide_done");
+        cmds.push(Instr::IMov(Reg(RAX), Imm(0)));
+        cmds.push(Instr::IJmp(done.clone()));
+        cmds.push(Instr::ILabel(divide));
+        cmds.push(Instr::ICdq);
+        cmds.push(Instr::IIDiv(Reg32(RCX)));
+        cmds.push(Instr::IMov(Reg(RAX), Reg(RDX)));
+        cmds.push(Instr::ILabel(done));
+    } else {
+        cmds.push(Instr::ICmp(Reg32(RAX), Imm(i32::MIN)));
+        cmds.push(Instr::IJe(String::from("overflow")));
+        cmds.push(Instr::ILabel(divide));
+        cmds.push(Instr::ICdq);
+        cmds.push(Instr::IIDiv(Reg32(RCX)));
+    }
+}
+
+// Leaves e1 in RCX and e2 in RAX, evaluating e1 first as interp does.
+fn compile_operand_instrs<'a>(
+    e1: &'a Expr,
+    e2: &'a Expr,
+    si: i32,
+    l: &mut i32,
+    cmds: &mut Vec<Instr>,
+    origins: &mut Origins<'a>,
+) {
+    compile_expr_instrs(e1, si, l, cmds, origins);
+    cmds.push(Instr::IMov(RegOffset(RSP, slot_offset(si)), Reg(RAX)));
+    compile_expr_instrs(e2, si + 1, l, cmds, origins);
+    cmds.push(Instr::IMov(Reg(RCX), RegOffset(RSP, slot_offset(si))));
+}
+
+fn compile_expr_instrs<'a>(
+    e: &'a Expr,
+    si: i32,
+    l: &mut i32,
+    cmds: &mut Vec<Instr>,
+    origins: &mut Origins<'a>,
+) {
+    let id = origins.nodes.len();
+    origins.nodes.push((e, origins.depth));
+    origins.depth += 1;
+    let start = cmds.len();
+    match &e.kind {
+        ExprKind::Num(n) => cmds.push(Instr::IMov(Reg(RAX), Imm(*n))),
+        ExprKind::Add1(subexpr) => {
+            compile_expr_instrs(subexpr, si, l, cmds, origins);
             cmds.push(Instr::IAdd(Reg(RAX), Imm(1)))
         }
-        Expr::Sub1(subexpr) => {
-            compile_expr_instrs(&subexpr, cmds);
+        ExprKind::Sub1(subexpr) => {
+            compile_expr_instrs(subexpr, si, l, cmds, origins);
             cmds.push(Instr::ISub(Reg(RAX), Imm(1)))
         }
+        ExprKind::Negate(subexpr) => {
+            compile_expr_instrs(subexpr, si, l, cmds, origins);
+            cmds.push(Instr::INeg(Reg(RAX)))
+        }
+        ExprKind::Mod(..) | ExprKind::Div(..) => compile_idiv_instrs(id, e, si, l, cmds, origins),
+        ExprKind::Plus(e1, e2) => {
+            compile_operand_instrs(e1, e2, si, l, cmds, origins);
+            cmds.push(Instr::IAdd(Reg(RAX), Reg(RCX)))
+        }
+        // Compare the low halves only: RAX and RCX hold 32-bit values whose
+        // upper bits aren't kept meaningful.
+        ExprKind::Min(e1, e2) => {
+            compile_operand_instrs(e1, e2, si, l, cmds, origins);
+            cmds.push(Instr::ICmp(Reg32(RAX), Reg32(RCX)));
+            cmds.push(Instr::ICmovg(Reg(RAX), Reg(RCX)))
+        }
+        ExprKind::Max(e1, e2) => {
+            compile_operand_instrs(e1, e2, si, l, cmds, origins);
+            cmds.push(Instr::ICmp(Reg32(RAX), Reg32(RCX)));
+            cmds.push(Instr::ICmovl(Reg(RAX), Reg(RCX)))
+        }
+    }
+    origins.depth -= 1;
+    // Subexpressions have already claimed their own instructions; whatever
+    // is left unclaimed in this node's range was emitted by the node itself.
+    origins.of_instr.resize(cmds.len(), None);
+    for o in &mut origins.of_instr[start..] {
+        o.get_or_insert(id);
+    }
+}
+
+fn error_handler_instrs(cmds: &mut Vec<Instr>) {
+    for (label, err) in [
+        ("divide_by_zero", RuntimeError::DivideByZero),
+        ("overflow", RuntimeError::Overflow),
+    ] {
+        cmds.push(Instr::ILabel(String::from(label)));
+        cmds.push(Instr::IMov(Reg(RDI), Imm(runtime_error_code(&err))));
+        cmds.push(Instr::IJmp(String::from("throw_error")));
+    }
+    cmds.push(Instr::ILabel(String::from("throw_error")));
+    // Realign the stack for the call; snek_error never returns.
+    cmds.push(Instr::ISub(Reg(RSP), Imm(8)));
+    cmds.push(Instr::ICall(String::from("snek_error")));
+}
+
+fn reads_rax(v: &Val) -> bool {
+    matches!(v, Reg(RAX) | Reg32(RAX) | RegOffset(RAX, _))
+}
+
+// Drops a mov into RAX that is immediately overwritten by another mov into
+// RAX, as long as the second one doesn't read RAX. Anything in between
+// (including a label someone could jump to) keeps the first mov alive.
+// `origins` is filtered in lockstep so it stays parallel to the result.
+fn eliminate_redundant_movs(instrs: Vec<Instr>, origins: &mut Vec<Option<usize>>) -> Vec<Instr> {
+    let mut out: Vec<Instr> = Vec::with_capacity(instrs.len());
+    let mut out_origins = Vec::with_capacity(origins.len());
+    for (i, origin) in instrs.into_iter().zip(origins.drain(..)) {
+        if let Instr::IMov(Reg(RAX), src) = &i {
+            if !reads_rax(src) && matches!(out.last(), Some(Instr::IMov(Reg(RAX), _))) {
+                out.pop();
+                out_origins.pop();
+            }
+        }
+        out.push(i);
+        out_origins.push(origin);
+    }
+    *origins = out_origins;
+    out
+}
+
+#[derive(Debug)]
+enum CompileError {
+    TooManyInstructions(usize),
+}
+
+fn compile_error_message(e: &CompileError) -> String {
+    match e {
+        CompileError::TooManyInstructions(limit) => {
+            format!("program compiles to more than {limit} instructions")
+        }
     }
 }
 
-fn compile_to_instrs(e: &Expr) -> Vec<Instr> {
+// Settings that change how a program is compiled, built once from the
+// command line and passed by reference rather than as loose parameters.
+#[derive(Default)]
+struct CompilerConfig {
+    // Bounds the whole listing, error handlers included.
+    max_instrs: Option<usize>,
+    result_extension: ResultExtension,
+}
+
+// How the 32-bit result in EAX is widened to the 64-bit RAX the caller reads.
+// The arithmetic runs on all of RAX, so without this the upper half holds
+// whatever the last add/sub/idiv left there.
+#[derive(Default)]
+enum ResultExtension {
+    #[default]
+    Sign,
+    Zero,
+}
+
+fn compile_to_instrs(e: &Expr, config: &CompilerConfig) -> Result<Vec<Instr>, CompileError> {
+    compile_to_instrs_with_origins(e, config).map(|(v, _)| v)
+}
+
+fn compile_to_instrs_with_origins<'a>(
+    e: &'a Expr,
+    config: &CompilerConfig,
+) -> Result<(Vec<Instr>, Origins<'a>), CompileError> {
     let mut v: Vec<Instr> = Vec::new();
-    compile_expr_instrs(e, &mut v);
-    return v;
+    let mut l = 0;
+    let mut origins = Origins::default();
+    compile_expr_instrs(e, 1, &mut l, &mut v, &mut origins);
+    let frame = with_frame(&mut v, &mut origins.of_instr);
+    v.push(match config.result_extension {
+        ResultExtension::Sign => Instr::IMovsxd(Reg(RAX), Reg32(RAX)),
+        // Writing a 32-bit register clears the upper half.
+        ResultExtension::Zero => Instr::IMov(Reg32(RAX), Reg32(RAX)),
+    });
+    if frame > 0 {
+        v.push(Instr::IAdd(Reg(RSP), Imm(frame)));
+    }
+    v.push(Instr::IRet);
+    error_handler_instrs(&mut v);
+    origins.of_instr.resize(v.len(), None);
+    let v = eliminate_redundant_movs(v, &mut origins.of_instr);
+    match config.max_instrs {
+        Some(limit) if v.len() > limit => Err(CompileError::TooManyInstructions(limit)),
+        _ => Ok((v, origins)),
+    }
+}
+
+// The source of a node squeezed onto one line.
+fn span_text(source: &str, span: &Span) -> String {
+    source[span.clone()].split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// instrs_to_str with a "; from: <source>" comment after every instruction
+// some node emitted, for --annotate.
+fn annotated_instrs_to_str(source: &str, instrs: &[Instr], origins: &Origins) -> String {
+    instrs
+        .iter()
+        .zip(&origins.of_instr)
+        .map(|(i, origin)| match origin {
+            Some(id) => {
+                let fragment = span_text(source, &origins.nodes[*id].0.span);
+                format!("{:<32}; from: {fragment}", instr_to_str(i))
+            }
+            None => instr_to_str(i),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// One line per AST node, in the order they were compiled and indented by
+// depth, listing the instructions that node emitted itself (not those of its
+// subexpressions). Nodes are shown as written, on one line.
+fn explain(source: &str, instrs: &[Instr], origins: &Origins) -> String {
+    let mut out = String::new();
+    for (id, (e, depth)) in origins.nodes.iter().enumerate() {
+        let own: Vec<String> = instrs
+            .iter()
+            .zip(&origins.of_instr)
+            .filter(|(_, o)| **o == Some(id))
+            .map(|(i, _)| instr_to_str(i))
+            .collect();
+        let own = if own.is_empty() { String::from("(no instructions)") } else { own.join(" / ") };
+        let fragment = span_text(source, &e.span);
+        out.push_str(&format!("{}{fragment} → {own}\n", "  ".repeat(*depth)));
+    }
+    out
+}
+
+#[cfg(target_os = "macos")]
+const NASM_FORMAT: &str = "macho64";
+#[cfg(not(target_os = "macos"))]
+const NASM_FORMAT: &str = "elf64";
+
+// Runtime calls in the .s go through the PLT on ELF, so the object can be
+// linked into a PIE or a shared library; Mach-O needs no marker for that.
+// (The JIT loads the absolute address into a register instead.)
+#[cfg(target_os = "macos")]
+const CALL_SUFFIX: &str = "";
+#[cfg(not(target_os = "macos"))]
+const CALL_SUFFIX: &str = " wrt ..plt";
+
+fn run_tool(cmd: &mut Command) -> std::io::Result<()> {
+    let status = cmd.status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!("{:?} failed with {}", cmd, status)))
+    }
+}
+
+// A directory under the system temp dir that is removed again when dropped,
+// however the function using it returns, unless `keep` is set.
+struct TempDir {
+    path: std::path::PathBuf,
+    keep: bool,
 }
 
-fn interp(e: &Expr) -> i32 {
+impl TempDir {
+    fn new(name: &str, keep: bool) -> std::io::Result<TempDir> {
+        let path = env::temp_dir().join(name);
+        std::fs::create_dir_all(&path)?;
+        Ok(TempDir { path, keep })
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if self.keep {
+            eprintln!("kept intermediate files in {}", self.path.display());
+        } else {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+// The runtime the Makefile links the .s against, built into the binary so
+// --verify doesn't depend on where the source tree is.
+const START_RS: &str = include_str!("../runtime/start.rs");
+
+// Builds the text assembly into an executable the way the Makefile does and
+// returns what it prints. Returns Ok(None) when nasm isn't installed. With
+// keep_temps the .s, .o, .a and executable are left in the temp directory,
+// even when a step fails.
+fn run_text_asm(asm_program: &str, keep_temps: bool) -> std::io::Result<Option<String>> {
+    if Command::new("nasm").arg("-v").output().is_err() {
+        return Ok(None);
+    }
+    let temp = TempDir::new(&format!("adder-verify-{}", std::process::id()), keep_temps)?;
+    let dir = &temp.path;
+    let asm_path = dir.join("our_code.s");
+    let obj_path = dir.join("our_code.o");
+    let exe_path = dir.join("program.run");
+    std::fs::write(&asm_path, asm_program)?;
+    run_tool(Command::new("nasm").args(["-f", NASM_FORMAT]).arg(&asm_path).arg("-o").arg(&obj_path))?;
+    run_tool(Command::new("ar").arg("rcs").arg(dir.join("libour_code.a")).arg(&obj_path))?;
+    let mut rustc = Command::new("rustc");
+    if cfg!(target_os = "macos") {
+        rustc.args(["--target", "x86_64-apple-darwin"]);
+    }
+    let start_rs = dir.join("start.rs");
+    std::fs::write(&start_rs, START_RS)?;
+    run_tool(rustc.arg("-L").arg(dir).arg(&start_rs).arg("-o").arg(&exe_path))?;
+    let output = Command::new(&exe_path).output()?;
+    let mut printed = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() {
+        printed.push_str(String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(Some(printed))
+}
+
+// True when the source has nothing but whitespace and ; comments, which
+// sexp would otherwise report as an unexpected eof.
+fn is_empty_program(s: &str) -> bool {
+    s.lines()
+        .map(|line| line.split(';').next().unwrap_or(""))
+        .all(|code| code.trim().is_empty())
+}
+
+// Shared by interp and interp_traced: checked_div returns None both for a zero
+// divisor and for i32::MIN / -1. A remainder is passed in as None only for a
+// zero divisor, since i32::MIN % -1 is 0 (see remainder).
+fn division_result(result: Option<i32>, divisor: i32) -> Result<i32, InterpError> {
+    match result {
+        Some(n) => Ok(n),
+        None if divisor == 0 => Err(InterpError::Runtime(RuntimeError::DivideByZero)),
+        None => Err(InterpError::Runtime(RuntimeError::Overflow)),
+    }
+}
+
+// n1 % n2, or None for a zero divisor.
+fn remainder(n1: i32, n2: i32) -> Option<i32> {
+    (n2 != 0).then(|| n1.wrapping_rem(n2))
+}
+
+// What interp can fail with: an error the compiled code would raise too, or
+// running out of --max-steps, which only the interpreter counts.
+#[derive(Debug)]
+enum InterpError {
+    Runtime(RuntimeError),
+    StepLimitExceeded,
+}
+
+fn interp_error_message(e: &InterpError) -> String {
     match e {
-        Expr::Num(n) => *n,
-        Expr::Add1(subexpr) => 1 + interp(subexpr),
-        Expr::Sub1(subexpr) => interp(subexpr) - 1
+        InterpError::Runtime(err) => String::from(runtime_error_message(runtime_error_code(err) as i64)),
+        InterpError::StepLimitExceeded => String::from("step limit exceeded"),
+    }
+}
+
+// Uses up one step of `budget` for evaluating `e`; None is unbounded.
+fn take_step(budget: &mut Option<u64>, e: &Expr) -> Result<(), (InterpError, Span)> {
+    match budget {
+        Some(0) => Err((InterpError::StepLimitExceeded, e.span.clone())),
+        Some(n) => {
+            *n -= 1;
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+// Errors carry the span of the node that failed. Every node evaluated costs
+// one step of `budget`.
+fn interp(e: &Expr, budget: &mut Option<u64>) -> Result<i32, (InterpError, Span)> {
+    take_step(budget, e)?;
+    match &e.kind {
+        ExprKind::Num(n) => Ok(*n),
+        // The generated code does 64-bit arithmetic and returns the low 32
+        // bits, so add1/sub1 wrap at the i32 boundaries.
+        ExprKind::Add1(subexpr) => Ok(interp(subexpr, budget)?.wrapping_add(1)),
+        ExprKind::Sub1(subexpr) => Ok(interp(subexpr, budget)?.wrapping_sub(1)),
+        // Likewise -i32::MIN wraps back to i32::MIN.
+        ExprKind::Negate(subexpr) => Ok(interp(subexpr, budget)?.wrapping_neg()),
+        ExprKind::Mod(e1, e2) => {
+            let (n1, n2) = (interp(e1, budget)?, interp(e2, budget)?);
+            division_result(remainder(n1, n2), n2).map_err(|err| (err, e.span.clone()))
+        }
+        ExprKind::Div(e1, e2) => {
+            let (n1, n2) = (interp(e1, budget)?, interp(e2, budget)?);
+            division_result(n1.checked_div(n2), n2).map_err(|err| (err, e.span.clone()))
+        }
+        ExprKind::Plus(e1, e2) => Ok(interp(e1, budget)?.wrapping_add(interp(e2, budget)?)),
+        ExprKind::Min(e1, e2) => Ok(interp(e1, budget)?.min(interp(e2, budget)?)),
+        ExprKind::Max(e1, e2) => Ok(interp(e1, budget)?.max(interp(e2, budget)?)),
+    }
+}
+
+fn expr_to_str(e: &Expr) -> String {
+    match &e.kind {
+        ExprKind::Num(n) => n.to_string(),
+        ExprKind::Add1(subexpr) => format!("(add1 {})", expr_to_str(subexpr)),
+        ExprKind::Sub1(subexpr) => format!("(sub1 {})", expr_to_str(subexpr)),
+        ExprKind::Negate(subexpr) => format!("(negate {})", expr_to_str(subexpr)),
+        ExprKind::Mod(e1, e2) => format!("(mod {} {})", expr_to_str(e1), expr_to_str(e2)),
+        ExprKind::Div(e1, e2) => format!("(/ {} {})", expr_to_str(e1), expr_to_str(e2)),
+        ExprKind::Plus(e1, e2) => format!("(+ {} {})", expr_to_str(e1), expr_to_str(e2)),
+        ExprKind::Min(e1, e2) => format!("(min {} {})", expr_to_str(e1), expr_to_str(e2)),
+        ExprKind::Max(e1, e2) => format!("(max {} {})", expr_to_str(e1), expr_to_str(e2)),
+    }
+}
+
+// Same evaluation as interp, but writes an "enter"/"exit" line for every node,
+// indented two spaces per level of nesting.
+fn interp_traced(
+    e: &Expr,
+    depth: usize,
+    w: &mut dyn Write,
+    budget: &mut Option<u64>,
+) -> Result<i32, (InterpError, Span)> {
+    let indent = "  ".repeat(depth);
+    writeln!(w, "{indent}enter {}", expr_to_str(e)).unwrap();
+    let result = take_step(budget, e).and_then(|()| match &e.kind {
+        ExprKind::Num(n) => Ok(*n),
+        ExprKind::Add1(subexpr) => interp_traced(subexpr, depth + 1, w, budget).map(|n| n.wrapping_add(1)),
+        ExprKind::Sub1(subexpr) => interp_traced(subexpr, depth + 1, w, budget).map(|n| n.wrapping_sub(1)),
+        ExprKind::Negate(subexpr) => interp_traced(subexpr, depth + 1, w, budget).map(|n| n.wrapping_neg()),
+        ExprKind::Mod(e1, e2) => interp_traced(e1, depth + 1, w, budget).and_then(|n1| {
+            let n2 = interp_traced(e2, depth + 1, w, budget)?;
+            division_result(remainder(n1, n2), n2).map_err(|err| (err, e.span.clone()))
+        }),
+        ExprKind::Div(e1, e2) => interp_traced(e1, depth + 1, w, budget).and_then(|n1| {
+            let n2 = interp_traced(e2, depth + 1, w, budget)?;
+            division_result(n1.checked_div(n2), n2).map_err(|err| (err, e.span.clone()))
+        }),
+        ExprKind::Plus(e1, e2) => interp_traced(e1, depth + 1, w, budget)
+            .and_then(|n1| Ok(n1.wrapping_add(interp_traced(e2, depth + 1, w, budget)?))),
+        ExprKind::Min(e1, e2) => interp_traced(e1, depth + 1, w, budget)
+            .and_then(|n1| Ok(n1.min(interp_traced(e2, depth + 1, w, budget)?))),
+        ExprKind::Max(e1, e2) => interp_traced(e1, depth + 1, w, budget)
+            .and_then(|n1| Ok(n1.max(interp_traced(e2, depth + 1, w, budget)?))),
+    });
+    match &result {
+        Ok(n) => writeln!(w, "{indent}exit {} = {n}", expr_to_str(e)).unwrap(),
+        Err((err, _)) => {
+            let message = interp_error_message(err);
+            writeln!(w, "{indent}exit {} with error: {message}", expr_to_str(e)).unwrap()
+        }
     }
+    result
 }
 
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+// Reads one program, returning the error already rendered for display along
+// with the exit code for it. `first_line` is as for render_error.
+fn parse_program(source: &str, first_line: usize, color: bool) -> Result<Expr, (i32, String)> {
+    let sexp = parse(source).map_err(|e| {
+        let error = ParseError::MalformedSexp(e.message.to_string());
+        let rendered = render_error(source, first_line, &parse_error_message(&error), Some(e.index), color);
+        (parse_error_exit_code(&error), rendered)
+    })?;
+    let spans = scan_spans(source, &mut 0);
+    parse_expr(&sexp, &spans).map_err(|(e, span)| {
+        let rendered = render_error(source, first_line, &parse_error_message(&e), Some(span.start), color);
+        (parse_error_exit_code(&e), rendered)
+    })
+}
 
-    let in_name = &args[1];
-    let out_name = &args[2];
+// Programs in a batch are separated by blank lines or form feeds. Each one
+// is returned as a slice of `source` along with the line it starts on, so
+// its errors can give lines in the file.
+fn split_batch(source: &str) -> Vec<(usize, &str)> {
+    let mut programs = Vec::new();
+    let (mut start, mut start_line) = (0, 1);
+    let mut pos = 0;
+    for (line_no, line) in (1..).zip(source.split_inclusive('\n')) {
+        if line.trim().is_empty() {
+            programs.push((start_line, &source[start..pos]));
+            (start, start_line) = (pos + line.len(), line_no + 1);
+        } else {
+            for (i, _) in line.match_indices('\x0c') {
+                programs.push((start_line, &source[start..pos + i]));
+                (start, start_line) = (pos + i + 1, line_no);
+            }
+        }
+        pos += line.len();
+    }
+    programs.push((start_line, &source[start..]));
+    programs.retain(|(_, p)| !is_empty_program(p));
+    programs
+}
 
-    let mut in_file = File::open(in_name)?;
-    let mut in_contents = String::new();
-    in_file.read_to_string(&mut in_contents)?;
+// Interprets each program in turn, printing one result per line. Errors are
+// reported on stderr with the program's 1-based index and don't stop the
+// batch. Returns the exit code for the first failure, or 0.
+fn run_batch(source: &str, fmt: &ResultFormat, color: bool, max_steps: Option<u64>) -> i32 {
+    let mut exit_code = 0;
+    for (i, (first_line, program)) in split_batch(source).into_iter().enumerate() {
+        let result = parse_program(program, first_line, color).and_then(|expr| {
+            interp(&expr, &mut { max_steps }).map_err(|(e, span)| {
+                let excerpt = source_excerpt(program, first_line, span.start, color);
+                let report = runtime_error_report(&interp_error_message(&e), Some(excerpt));
+                (EXIT_RUNTIME_ERROR, report)
+            })
+        });
+        match result {
+            Ok(n) => println!("{}", format_result(n.into(), fmt)),
+            Err((code, rendered)) => {
+                if exit_code == 0 {
+                    exit_code = code;
+                }
+                eprint!("program {}: {}", i + 1, rendered);
+            }
+        }
+    }
+    exit_code
+}
 
-    let expr = parse_expr(&parse(&in_contents).unwrap());
-    let instrs = compile_to_instrs(&expr);
-    let result = instrs_to_str(&instrs);
+// Times `runs` interp calls against compiling once and making `runs` JIT
+// calls, and reports after how many runs the JIT pays for its compile cost.
+fn compare_modes(expr: &Expr, config: &CompilerConfig, runs: u32) {
+    let start = Instant::now();
+    for _ in 0..runs {
+        let _ = black_box(interp(black_box(expr), &mut None));
+    }
+    let interp_per_call = start.elapsed() / runs;
+
+    let start = Instant::now();
+    let instrs = compile_to_instrs(expr, config).unwrap_or_else(|e| {
+        eprintln!("{}", compile_error_message(&e));
+        std::process::exit(EXIT_COMPILE_ERROR);
+    });
+    let jitted = JitFunction::new(&instrs).unwrap_or_else(|e| {
+        eprintln!("{}", jit_error_message(&e));
+        std::process::exit(EXIT_USAGE_ERROR);
+    });
+    let compile_cost = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..runs {
+        black_box(jitted.call());
+    }
+    let jit_per_call = start.elapsed() / runs;
+
+    println!("compile cost:         {:?}", compile_cost);
+    println!("JIT cost per call:    {:?}", jit_per_call);
+    println!("interp cost per call: {:?}", interp_per_call);
+    if interp_per_call > jit_per_call {
+        let saved = (interp_per_call - jit_per_call).as_nanos();
+        let crossover = compile_cost.as_nanos().div_ceil(saved);
+        println!("JIT wins after {} runs", crossover);
+    } else {
+        println!("JIT never wins: interp is at least as fast per call");
+    }
+}
+
+struct ResultFormat {
+    show_hex: bool,
+    // Show the 32 bits of the result as an unsigned number.
+    unsigned: bool,
+    radix: u32,
+    // A fixed "dec=N hex=0x..." line for scripts; the other fields are
+    // ignored.
+    keyval: bool,
+}
+
+// std's formatting only covers bases 2, 8, 10 and 16.
+fn to_radix(mut n: u64, radix: u32) -> String {
+    let mut digits = Vec::new();
+    loop {
+        digits.push(char::from_digit((n % radix as u64) as u32, radix).unwrap());
+        n /= radix as u64;
+        if n == 0 {
+            break;
+        }
+    }
+    digits.iter().rev().collect()
+}
+
+// --unsigned and the hex suffix look at the low 32 bits, the program's own
+// result; the signed view shows n as the caller received it.
+fn format_result(n: i64, fmt: &ResultFormat) -> String {
+    if fmt.keyval {
+        return format!("dec={n} hex={:#x}", n as u32);
+    }
+    let (negative, magnitude) = if fmt.unsigned {
+        (false, n as u32 as u64)
+    } else {
+        (n < 0, n.unsigned_abs())
+    };
+    let prefix = match fmt.radix {
+        2 => "0b",
+        8 => "0o",
+        16 => "0x",
+        _ => "",
+    };
+    let sign = if negative { "-" } else { "" };
+    let value = format!("{sign}{prefix}{}", to_radix(magnitude, fmt.radix));
+    if fmt.show_hex {
+        format!("{value} ({:#x})", n as u32)
+    } else {
+        value
+    }
+}
+
+// For --result-as-exit-code: exit statuses are 8 bits, so the result is
+// truncated to its low byte, two's complement (-1 exits 255, 256 exits 0).
+fn result_exit_code(n: i64) -> i32 {
+    (n as u8).into()
+}
+
+// What goes in the output file: the complete .s, or only the instruction
+// lines (no section/global header) for diffing codegen. Asking for either
+// with --emit writes the file and stops there, without the JIT.
+enum Emit {
+    Asm,
+    InstrsText,
+}
+
+fn run(mut flags: impl Iterator<Item = String>) -> std::io::Result<()> {
+    let mut args: Vec<String> = Vec::new();
+    let mut run_count: usize = 1;
+    let mut config = CompilerConfig::default();
+    let mut quiet = false;
+    let mut verify = false;
+    let mut keep_temps = false;
+    let mut result_format = ResultFormat {
+        show_hex: false,
+        unsigned: false,
+        radix: 10,
+        keyval: false,
+    };
+    let mut interp_only = false;
+    let mut trace_interp = false;
+    let mut batch = false;
+    let mut compare_runs: Option<u32> = None;
+    let mut asm_banner = false;
+    let mut diagnose = false;
+    let mut explain_only = false;
+    let mut report_stack = false;
+    let mut emit: Option<Emit> = None;
+    let mut result_as_exit_code = false;
+    let mut max_steps: Option<u64> = None;
+    let mut annotate = false;
+    let mut timeout: Option<Duration> = None;
+    let mut color = std::io::stderr().is_terminal()
+        && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty());
+    let mut inline_program: Option<String> = None;
+    while let Some(arg) = flags.next() {
+        match arg.as_str() {
+            "--run-count" => {
+                run_count = match flags.next().map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) if n > 0 => n,
+                    _ => usage_error("--run-count expects a positive number"),
+                }
+            }
+            "--max-instrs" => {
+                config.max_instrs = match flags.next().map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) => Some(n),
+                    _ => usage_error("--max-instrs expects a number"),
+                }
+            }
+            "--timeout" => {
+                timeout = match flags.next().map(|n| n.parse::<u64>()) {
+                    Some(Ok(ms)) => Some(Duration::from_millis(ms)),
+                    _ => usage_error("--timeout expects a number of milliseconds"),
+                }
+            }
+            "--zero-extend" => config.result_extension = ResultExtension::Zero,
+            "--quiet" => quiet = true,
+            "--verify" => verify = true,
+            "--keep-temps" => keep_temps = true,
+            "--show-hex" => result_format.show_hex = true,
+            "--format=KEYVAL" => result_format.keyval = true,
+            "--result-as-exit-code" => result_as_exit_code = true,
+            "--annotate" => annotate = true,
+            "--max-steps" => {
+                max_steps = match flags.next().map(|n| n.parse::<u64>()) {
+                    Some(Ok(n)) => Some(n),
+                    _ => usage_error("--max-steps expects a number"),
+                }
+            }
+            "--unsigned" => result_format.unsigned = true,
+            "--radix" => {
+                result_format.radix = match flags.next().map(|n| n.parse::<u32>()) {
+                    Some(Ok(n)) if (2..=36).contains(&n) => n,
+                    _ => usage_error("--radix expects a base from 2 to 36"),
+                }
+            }
+            "--interp" => interp_only = true,
+            "--trace-interp" => trace_interp = true,
+            "--batch" => batch = true,
+            "--compare-modes" => {
+                compare_runs = match flags.next().map(|n| n.parse::<u32>()) {
+                    Some(Ok(n)) if n > 0 => Some(n),
+                    _ => usage_error("--compare-modes expects a positive number of runs"),
+                }
+            }
+            "--asm-banner" => asm_banner = true,
+            "--diagnose" => diagnose = true,
+            "--explain" => explain_only = true,
+            "--report-stack" => report_stack = true,
+            "--emit=asm" => emit = Some(Emit::Asm),
+            "--emit=instrs-text" => emit = Some(Emit::InstrsText),
+            "--no-color" => color = false,
+            "-e" => match flags.next() {
+                Some(program) => inline_program = Some(program),
+                None => usage_error("-e expects a program"),
+            },
+            _ if arg.starts_with('-') => usage_error(&format!("unknown option {arg}")),
+            _ => args.push(arg),
+        }
+    }
+    // The interpreter never produces instructions to write out.
+    if emit.is_some() && (interp_only || trace_interp || batch) {
+        usage_error("--emit cannot be combined with --interp, --trace-interp or --batch");
+    }
+
+    // With -e the program comes from the command line and no .s file is
+    // written; otherwise the positional arguments are the input and an
+    // optional output file.
+    let (in_contents, out_name) = match inline_program {
+        Some(program) => {
+            if !args.is_empty() {
+                usage_error("cannot use -e together with an input file");
+            }
+            (program, None)
+        }
+        None => {
+            let (Some(in_name), 1..=2) = (args.first(), args.len()) else {
+                usage_error("usage: adder [options] <input.snek> [output.s]");
+            };
+            let mut in_file = File::open(in_name)?;
+            let mut in_contents = String::new();
+            in_file.read_to_string(&mut in_contents)?;
+            (in_contents, args.get(1))
+        }
+    };
+
+    if batch {
+        std::process::exit(run_batch(&in_contents, &result_format, color, max_steps));
+    }
+
+    if is_empty_program(&in_contents) {
+        eprintln!("empty program: expected an expression");
+        std::process::exit(EXIT_PARSE_ERROR);
+    }
+
+    let expr = match parse_program(&in_contents, 1, color) {
+        Ok(expr) => expr,
+        Err((code, rendered)) => {
+            eprint!("{}", rendered);
+            std::process::exit(code);
+        }
+    };
+    if let Some(runs) = compare_runs {
+        // Both modes must finish for the timings to mean anything.
+        if let Err((e, span)) = interp(&expr, &mut None) {
+            report_interp_error(&in_contents, color, &e, &span);
+        }
+        compare_modes(&expr, &config, runs);
+        return Ok(());
+    }
+
+    // interp matches on every Expr variant without a wildcard, so it can't
+    // fall behind the compiler; skip codegen and the JIT entirely here.
+    if interp_only || trace_interp {
+        let result = if trace_interp {
+            interp_traced(&expr, 0, &mut std::io::stdout(), &mut { max_steps })
+        } else {
+            interp(&expr, &mut { max_steps })
+        };
+        match result {
+            Ok(n) => {
+                println!("{}", format_result(n.into(), &result_format));
+                if result_as_exit_code {
+                    std::process::exit(result_exit_code(n.into()));
+                }
+            }
+            Err((e, span)) => report_interp_error(&in_contents, color, &e, &span),
+        }
+        return Ok(());
+    }
+
+    let (instrs, origins) = match compile_to_instrs_with_origins(&expr, &config) {
+        Ok(compiled) => compiled,
+        Err(e) => {
+            eprint!("{}", render_error(&in_contents, 1, &compile_error_message(&e), None, color));
+            std::process::exit(EXIT_COMPILE_ERROR);
+        }
+    };
+    *ERROR_SITES.lock().unwrap() = Some(ErrorSites {
+        source: in_contents.clone(),
+        spans: origins.nodes.iter().map(|(e, _)| e.span.clone()).collect(),
+        color,
+    });
+    if report_stack {
+        let bytes = peak_stack_bytes(&instrs);
+        println!("peak stack: {bytes} bytes ({} slots)", bytes / WORD_SIZE);
+        match frame_bytes(&instrs) {
+            0 => println!("fits in the {RED_ZONE_SIZE}-byte red zone"),
+            frame => println!("exceeds the {RED_ZONE_SIZE}-byte red zone; allocates a {frame}-byte frame"),
+        }
+        return Ok(());
+    }
+    if explain_only {
+        print!("{}", explain(&in_contents, &instrs, &origins));
+        return Ok(());
+    }
+    let result = if annotate {
+        annotated_instrs_to_str(&in_contents, &instrs, &origins)
+    } else {
+        instrs_to_str(&instrs)
+    };
+    let banner = if asm_banner {
+        // The output is NASM syntax; GNU as would misread it.
+        let name = out_name.map_or("out.s", |n| n.as_str());
+        format!("; NASM syntax, assemble with: nasm -f {NASM_FORMAT} {name}")
+    } else {
+        String::new()
+    };
     let asm_program = format!(
-        "
+        "{}
+default rel
 section .text
+extern snek_error
 global our_code_starts_here
 our_code_starts_here:
   {}
-  ret
 ",
-        result
+        banner, result
     );
 
-    let mut out_file = File::create(out_name)?;
-    out_file.write_all(asm_program.as_bytes())?;
-
-    let mut ops = dynasmrt::x64::Assembler::new().unwrap();
-    let start = ops.offset();
-
-    instrs_to_asm(&instrs, &mut ops);
+    if let Some(out_name) = out_name {
+        let mut out_file = File::create(out_name)?;
+        match emit {
+            None | Some(Emit::Asm) => out_file.write_all(asm_program.as_bytes())?,
+            Some(Emit::InstrsText) => writeln!(out_file, "{result}")?,
+        }
+    } else if emit.is_some() {
+        usage_error("--emit needs an output file");
+    }
+    if emit.is_some() {
+        return Ok(());
+    }
 
-    dynasm!(ops
-    ; .arch x64
-    ; ret);
-    ops.commit();
-    let jitted_fn : extern "C" fn() -> i32 = {
-      let reader = ops.reader();
-      let buf = reader.lock();
-      unsafe { mem::transmute(buf.ptr(start)) }
+    // The .s has already been written, so it's still usable if this fails.
+    let mut jitted = match JitFunction::new(&instrs) {
+        Ok(jitted) => jitted,
+        Err(e) => {
+            eprint!("{}", render_error(&in_contents, 1, &jit_error_message(&e), None, color));
+            std::process::exit(EXIT_USAGE_ERROR);
+        }
     };
 
-    println!("Generated assembly:\n{}", asm_program);
-    println!("Result from long-form code:\n{}", jitted_fn());
+    if diagnose {
+        println!("=== input ===\n{}", in_contents.trim_end());
+        println!("=== ast ===\n{:#?}", expr);
+        println!("=== instructions ===");
+        for i in &instrs {
+            println!("{:?}", i);
+        }
+        println!("=== assembly ===\n{}", asm_program.trim());
+        let protection = jitted.protection();
+        println!("=== protection ===\n{}", protection.as_deref().unwrap_or("unknown"));
+        println!("=== result ===\n{}", format_result(jitted.call(), &result_format));
+        return Ok(());
+    }
+
+    if !quiet {
+        println!("Generated assembly:\n{}", asm_program);
+        println!("Result from long-form code:");
+    }
+    // Each call reruns the whole program from a fresh stack frame.
+    let mut result = 0;
+    for i in 0..run_count {
+        result = match timeout {
+            None => jitted.call(),
+            Some(limit) => jitted.call_with_timeout(limit).unwrap_or_else(|| {
+                eprintln!("execution timed out");
+                std::process::exit(EXIT_RUNTIME_ERROR);
+            }),
+        };
+        if !quiet || i == run_count - 1 {
+            println!("{}", format_result(result, &result_format));
+        }
+    }
+    if verify {
+        match run_text_asm(&asm_program, keep_temps)? {
+            None => eprintln!("--verify skipped: nasm is not installed"),
+            Some(printed) if printed == result.to_string() => {}
+            Some(printed) => {
+                eprintln!("verify failed: the assembled .s printed {printed:?} but the JIT returned {result}");
+                std::process::exit(EXIT_VERIFY_FAILED);
+            }
+        }
+    }
+    if result_as_exit_code {
+        std::process::exit(result_exit_code(result));
+    }
+    if quiet {
+        return Ok(());
+    }
 
-    let answer = interp(&expr) * 3; // multiply by 3 so we can see the effect
-    ops.alter(|modifier| {
+    let answer = match interp(&expr, &mut None) {
+        Ok(n) => n.wrapping_mul(3), // multiply by 3 so we can see the effect
+        Err((e, span)) => report_interp_error(&in_contents, color, &e, &span),
+    };
+    let entry_before = jitted.entry();
+    jitted.alter(|modifier| {
       dynasm!(modifier
       ; .arch x64
       ; mov rax, answer
       ; ret
       )
-    }).unwrap();
-    ops.commit(); // is this necessary? probably
-    // So, you could just call jitted_fn again (it “works”, but probably not
-    // always). I think this is safer (?) because the reader() is designed
-    // to make sure everything is finalized and read only before jumping and
-    // executing. Hard to test the failure case.
-    let jitted_fn_again : extern "C" fn() -> i32 = {
-      let reader = ops.reader();
-      let buf = reader.lock();
-      unsafe { mem::transmute(buf.ptr(start)) }
-    };
+    });
     {
-      println!("Rewritten to hardcode 3x the value directly:\n{}", jitted_fn_again());
-      println!("Did the value move? {:?} {:?}", jitted_fn, jitted_fn_again);
+      println!("Rewritten to hardcode 3x the value directly:\n{}", jitted.call());
+      println!("Did the value move? {:?} {:?}", entry_before, jitted.entry());
     }
 
     Ok(())
 }
+
+fn main() {
+    if let Err(e) = run(env::args().skip(1)) {
+        eprintln!("error: {e}");
+        std::process::exit(EXIT_USAGE_ERROR);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_ok(source: &str) -> Expr {
+        parse_program(source, 1, false).unwrap_or_else(|(_, e)| panic!("{source}: {e}"))
+    }
+
+    // (interp, JIT) for a program that runs without a runtime error; the JIT
+    // would report one by exiting the test process.
+    fn run_both(source: &str) -> (i64, i64) {
+        let expr = parse_ok(source);
+        let interp_result = match interp(&expr, &mut None) {
+            Ok(n) => n.into(),
+            Err((e, _)) => panic!("{source}: {}", interp_error_message(&e)),
+        };
+        let instrs = compile_to_instrs(&expr, &CompilerConfig::default()).unwrap();
+        (interp_result, JitFunction::new(&instrs).unwrap().call())
+    }
+
+    // run() exits the process on errors, so it's run in a copy of this test
+    // binary: adder_child below picks the arguments up from the environment.
+    // Returns the exit code, stdout and stderr.
+    fn run_adder(args: &[&str]) -> (i32, String, String) {
+        let output = Command::new(env::current_exe().unwrap())
+            .args(["--exact", "tests::adder_child", "--nocapture", "--test-threads=1", "-q"])
+            .env("ADDER_CHILD_ARGS", args.join("\x1f"))
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // Drop the test harness's own "running 1 test" line.
+        let stdout = stdout.split_once("running 1 test\n").map_or(&*stdout, |(_, rest)| rest);
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        (output.status.code().unwrap(), stdout.to_string(), stderr)
+    }
+
+    #[test]
+    fn adder_child() {
+        if let Ok(args) = env::var("ADDER_CHILD_ARGS") {
+            let args = args.split_terminator('\x1f').map(String::from).collect::<Vec<_>>();
+            let code = match run(args.into_iter()) {
+                Ok(()) => 0,
+                Err(_) => EXIT_USAGE_ERROR,
+            };
+            std::io::stdout().flush().unwrap();
+            std::process::exit(code);
+        }
+    }
+
+    #[test]
+    fn jit_and_interp_agree_at_the_boundaries() {
+        for (source, expected) in [
+            ("2147483647", i32::MAX),
+            ("-2147483648", i32::MIN),
+            ("(add1 2147483647)", i32::MIN),
+            ("(sub1 -2147483648)", i32::MAX),
+            ("(sub1 2147483647)", i32::MAX - 1),
+            ("(add1 -2147483648)", i32::MIN + 1),
+            ("(negate -2147483648)", i32::MIN),
+            ("(negate 2147483647)", -i32::MAX),
+            ("(+ 2147483647 2147483647)", -2),
+            ("(/ -2147483648 1)", i32::MIN),
+            ("(mod -2147483648 2147483647)", -1),
+            ("(mod -2147483648 -1)", 0),
+            ("(mod 7 -1)", 0),
+            ("(/ 2147483647 -1)", -i32::MAX),
+            ("(min 2147483647 -2147483648)", i32::MIN),
+            ("(max 2147483647 -2147483648)", i32::MAX),
+            ("(negate 0)", 0),
+        ] {
+            assert_eq!(run_both(source), (expected.into(), expected.into()), "{source}");
+        }
+    }
+
+    #[test]
+    fn division_evaluates_the_dividend_first() {
+        for source in ["(/ (/ -2147483648 -1) (/ 1 0))", "(mod (/ -2147483648 -1) (mod 1 0))"] {
+            for mode in ["--quiet", "--interp"] {
+                let (code, _, stderr) = run_adder(&[mode, "--no-color", "-e", source]);
+                let first_line = stderr.lines().next();
+                assert_eq!((code, first_line), (EXIT_RUNTIME_ERROR, Some("an error occurred: overflow")), "{source} {mode}");
+            }
+        }
+        assert_eq!(run_both("(/ (add1 6) (sub1 3))"), (3, 3));
+        assert_eq!(run_both("(mod (add1 6) (sub1 3))"), (1, 1));
+    }
+
+    #[test]
+    fn each_error_class_has_its_own_exit_code() {
+        for (args, expected) in [
+            (&["-e", "(add1 5"][..], EXIT_PARSE_ERROR),
+            (&["-e", "(add1 ())"], EXIT_PARSE_ERROR),
+            (&["-e", "99999999999"], EXIT_PARSE_ERROR),
+            (&["-e", "   "], EXIT_PARSE_ERROR),
+            (&["-e", "(add1 x)"], EXIT_COMPILE_ERROR),
+            (&["-e", "(add1 5 6)"], EXIT_COMPILE_ERROR),
+            (&["-e", "(+ 5)"], EXIT_COMPILE_ERROR),
+            (&["--max-instrs", "1", "-e", "5"], EXIT_COMPILE_ERROR),
+            (&["--quiet", "-e", "(/ 1 0)"], EXIT_RUNTIME_ERROR),
+            (&["--interp", "-e", "(mod 1 0)"], EXIT_RUNTIME_ERROR),
+            (&["--radix", "99", "-e", "5"], EXIT_USAGE_ERROR),
+            (&["-e"], EXIT_USAGE_ERROR),
+            (&["test/no_such_file.snek"], EXIT_USAGE_ERROR),
+            (&["--quiet", "-e", "5"], 0),
+        ] {
+            assert_eq!(run_adder(args).0, expected, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn jit_reports_missing_executable_memory() {
+        let denied = std::io::Error::other("mmap denied");
+        let e = JitFunction::with_assembler(Err(denied), &[Instr::IRet]).err().unwrap();
+        assert!(matches!(&e, JitError::NoExecutableMemory(cause) if cause == "mmap denied"));
+        assert_eq!(
+            jit_error_message(&e),
+            "could not allocate executable memory for JIT (mmap denied); use --interp or --emit=asm instead"
+        );
+    }
+
+    #[test]
+    fn emit_writes_the_file_without_running_the_program() {
+        let out = env::temp_dir().join(format!("adder-test-{}-emit.s", std::process::id()));
+        let out_name = out.to_str().unwrap();
+        let (code, stdout, _) = run_adder(&["--emit=asm", "test/add.snek", out_name]);
+        assert_eq!((code, stdout.as_str()), (0, ""));
+        assert!(std::fs::read_to_string(&out).unwrap().contains("our_code_starts_here:"));
+        std::fs::remove_file(&out).unwrap();
+        assert_eq!(run_adder(&["--emit=asm", "-e", "5"]).0, EXIT_USAGE_ERROR);
+    }
+
+    #[test]
+    fn redundant_movs_are_dropped_with_their_origins() {
+        let instrs = vec![
+            Instr::IMov(Reg(RAX), Imm(1)),
+            Instr::IMov(Reg(RAX), Imm(2)),
+            Instr::IMov(Reg(RAX), RegOffset(RAX, 8)),
+            Instr::ILabel(String::from("l")),
+            Instr::IMov(Reg(RAX), Imm(3)),
+            Instr::IMov(Reg(RCX), Imm(4)),
+            Instr::IMov(Reg(RAX), RegOffset(RSP, 8)),
+            Instr::IRet,
+        ];
+        let mut origins = vec![Some(0), Some(1), Some(2), None, Some(3), Some(4), Some(5), None];
+        let instrs = eliminate_redundant_movs(instrs, &mut origins);
+        assert_eq!(
+            instrs_to_str(&instrs),
+            "mov RAX, 2\nmov RAX, QWORD [RAX - 8]\nl:\nmov RAX, 3\nmov RCX, 4\nmov RAX, QWORD [RSP - 8]\nret"
+        );
+        assert_eq!(origins, [Some(1), Some(2), None, Some(3), Some(4), Some(5), None]);
+        assert_eq!(run_both("(+ 1 (+ 2 3))"), (6, 6));
+    }
+
+    fn parse_err(source: &str) -> String {
+        parse_program(source, 1, false).err().unwrap().1
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_column() {
+        assert_eq!(
+            parse_err("(foo 5)"),
+            "error: parse error: invalid expression (foo 5)\n1 | (foo 5)\n  | ^\n"
+        );
+        assert_eq!(
+            parse_err("(add1 (sub1 x))"),
+            "error: unbound identifier x\n1 | (add1 (sub1 x))\n  |             ^\n"
+        );
+        assert_eq!(
+            parse_err("(+ 1\n   (add1\n      y))"),
+            "error: unbound identifier y\n3 |       y))\n  |       ^\n"
+        );
+        assert_eq!(
+            render_error("1\n2\n3\n4\n5\n6\n7\n8\n9\n  (x)", 1, "boom", Some(20), false),
+            "error: boom\n10 |   (x)\n   |   ^\n"
+        );
+        assert_eq!(render_error("5", 1, "boom", None, false), "error: boom\n");
+    }
+
+    #[test]
+    fn max_instrs_counts_the_whole_listing() {
+        let expr = parse_ok("(add1 (sub1 5))");
+        let n = compile_to_instrs(&expr, &CompilerConfig::default()).unwrap().len();
+        let limited = |limit| CompilerConfig { max_instrs: Some(limit), ..Default::default() };
+        assert_eq!(compile_to_instrs(&expr, &limited(n)).unwrap().len(), n);
+        assert!(matches!(
+            compile_to_instrs(&expr, &limited(n - 1)),
+            Err(CompileError::TooManyInstructions(limit)) if limit == n - 1
+        ));
+    }
+
+    fn trace(source: &str, max_steps: Option<u64>) -> String {
+        let mut out = Vec::new();
+        let _ = interp_traced(&parse_ok(source), 0, &mut out, &mut { max_steps });
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn trace_enters_and_exits_every_node() {
+        assert_eq!(
+            trace("(add1 (sub1 5))", None),
+            "enter (add1 (sub1 5))\n  enter (sub1 5)\n    enter 5\n    exit 5 = 5\n  exit (sub1 5) = 4\nexit (add1 (sub1 5)) = 5\n"
+        );
+        assert_eq!(
+            trace("(+ 1 (/ 2 0))", None),
+            "enter (+ 1 (/ 2 0))\n  enter 1\n  exit 1 = 1\n  enter (/ 2 0)\n    enter 2\n    exit 2 = 2\n    enter 0\n    exit 0 = 0\n  exit (/ 2 0) with error: divide by zero\nexit (+ 1 (/ 2 0)) with error: divide by zero\n"
+        );
+        assert_eq!(
+            trace("(add1 (sub1 5))", Some(2)),
+            "enter (add1 (sub1 5))\n  enter (sub1 5)\n    enter 5\n    exit 5 with error: step limit exceeded\n  exit (sub1 5) with error: step limit exceeded\nexit (add1 (sub1 5)) with error: step limit exceeded\n"
+        );
+    }
+
+    #[test]
+    fn explain_lists_each_nodes_own_instructions() {
+        let source = "(add1 (sub1 5))";
+        let expr = parse_ok(source);
+        let (instrs, origins) = compile_to_instrs_with_origins(&expr, &CompilerConfig::default()).unwrap();
+        assert_eq!(
+            explain(source, &instrs, &origins),
+            "(add1 (sub1 5)) → add RAX, 1\n  (sub1 5) → sub RAX, 1\n    5 → mov RAX, 5\n"
+        );
+    }
+
+    #[test]
+    fn annotate_tags_the_instructions_a_node_emitted() {
+        let source = "(add1 (sub1 5))";
+        let expr = parse_ok(source);
+        let (instrs, origins) = compile_to_instrs_with_origins(&expr, &CompilerConfig::default()).unwrap();
+        let annotated = annotated_instrs_to_str(source, &instrs, &origins);
+        let lines: Vec<&str> = annotated.lines().collect();
+        assert_eq!(
+            lines[..5],
+            [
+                "mov RAX, 5                      ; from: 5",
+                "sub RAX, 1                      ; from: (sub1 5)",
+                "add RAX, 1                      ; from: (add1 (sub1 5))",
+                "movsxd RAX, EAX",
+                "ret",
+            ]
+        );
+        assert!(lines[5..].iter().all(|line| !line.contains("; from:")));
+    }
+
+    #[test]
+    fn nodes_carry_their_source_spans() {
+        let expr = parse_ok("(add1 5)");
+        assert_eq!(expr.span, 0..8);
+        let ExprKind::Add1(inner) = &expr.kind else { panic!("{expr:?}") };
+        assert_eq!(inner.span, 6..7);
+        assert!(matches!(inner.kind, ExprKind::Num(5)));
+
+        // Comments, line breaks and redundant parentheses are skipped over.
+        let source = "; seven\n(+ (1)\n   (add1 5))";
+        let expr = parse_ok(source);
+        let ExprKind::Plus(e1, e2) = &expr.kind else { panic!("{expr:?}") };
+        assert_eq!((&source[expr.span.clone()], &source[e1.span.clone()]), ("(+ (1)\n   (add1 5))", "1"));
+        assert_eq!(&source[e2.span.clone()], "(add1 5)");
+    }
+
+    fn plain_format() -> ResultFormat {
+        ResultFormat { show_hex: false, unsigned: false, radix: 10, keyval: false }
+    }
+
+    #[test]
+    fn show_hex_adds_the_32_bit_pattern() {
+        let hex = ResultFormat { show_hex: true, ..plain_format() };
+        assert_eq!(format_result(255, &hex), "255 (0xff)");
+        assert_eq!(format_result(-1, &hex), "-1 (0xffffffff)");
+        assert_eq!(format_result(i32::MIN.into(), &hex), "-2147483648 (0x80000000)");
+        assert_eq!(format_result(255, &plain_format()), "255");
+        let (code, stdout, _) = run_adder(&["--show-hex", "--quiet", "-e", "(add1 254)"]);
+        assert_eq!((code, stdout.as_str()), (0, "255 (0xff)\n"));
+    }
+
+    #[test]
+    fn unsigned_reinterprets_the_low_32_bits() {
+        let unsigned = ResultFormat { unsigned: true, ..plain_format() };
+        assert_eq!(format_result(-1, &unsigned), "4294967295");
+        assert_eq!(format_result(i32::MIN.into(), &unsigned), "2147483648");
+        assert_eq!(format_result(42, &unsigned), "42");
+        assert_eq!(format_result(-1, &plain_format()), "-1");
+        let (code, stdout, _) = run_adder(&["--unsigned", "--quiet", "-e", "(sub1 0)"]);
+        assert_eq!((code, stdout.as_str()), (0, "4294967295\n"));
+    }
+
+    #[test]
+    fn radix_prints_in_any_base_from_2_to_36() {
+        let base = |radix| ResultFormat { radix, ..plain_format() };
+        assert_eq!(format_result(255, &base(2)), "0b11111111");
+        assert_eq!(format_result(255, &base(16)), "0xff");
+        assert_eq!(format_result(8, &base(8)), "0o10");
+        assert_eq!(format_result(-255, &base(16)), "-0xff");
+        assert_eq!(format_result(0, &base(2)), "0b0");
+        assert_eq!(format_result(35, &base(36)), "z");
+        assert_eq!(format_result(7, &base(3)), "21");
+        let unsigned_hex = ResultFormat { unsigned: true, ..base(16) };
+        assert_eq!(format_result(-1, &unsigned_hex), "0xffffffff");
+        let (code, stdout, _) = run_adder(&["--radix", "2", "--quiet", "-e", "(add1 254)"]);
+        assert_eq!((code, stdout.as_str()), (0, "0b11111111\n"));
+    }
+
+    #[test]
+    fn keyval_is_a_fixed_line_whatever_the_other_options() {
+        let keyval = ResultFormat { keyval: true, ..plain_format() };
+        assert_eq!(format_result(42, &keyval), "dec=42 hex=0x2a");
+        assert_eq!(format_result(-1, &keyval), "dec=-1 hex=0xffffffff");
+        let everything = ResultFormat { show_hex: true, unsigned: true, radix: 2, keyval: true };
+        assert_eq!(format_result(42, &everything), "dec=42 hex=0x2a");
+        let (code, stdout, _) = run_adder(&["--format=KEYVAL", "--quiet", "-e", "(+ 40 2)"]);
+        assert_eq!((code, stdout.as_str()), (0, "dec=42 hex=0x2a\n"));
+    }
+
+    #[test]
+    fn slots_are_word_aligned_and_disjoint() {
+        for i in 1..64 {
+            assert_eq!(slot_offset(i) % WORD_SIZE, 0);
+            assert_eq!(slot_offset(i + 1) - slot_offset(i), WORD_SIZE);
+        }
+        // The first slot sits entirely below RSP.
+        assert_eq!(slot_offset(1), WORD_SIZE);
+        // Nested operands of (+ a (+ b c)) take one slot per level.
+        let expr = parse_ok("(+ 1 (+ 2 (+ 3 4)))");
+        let instrs = compile_to_instrs(&expr, &CompilerConfig::default()).unwrap();
+        assert_eq!(peak_stack_bytes(&instrs), slot_offset(3));
+    }
+
+    // The bytes dynasm assembles for `instrs`.
+    fn machine_code(instrs: &[Instr]) -> Vec<u8> {
+        let jitted = JitFunction::new(instrs).unwrap();
+        let reader = jitted.ops.reader();
+        let buf = reader.lock();
+        buf[jitted.start.0..].to_vec()
+    }
+
+    #[test]
+    fn register_names_match_their_encodings() {
+        for (r, name, name32, index) in [
+            (RAX, "RAX", "EAX", 0),
+            (RCX, "RCX", "ECX", 1),
+            (RDX, "RDX", "EDX", 2),
+            (RSP, "RSP", "ESP", 4),
+            (RSI, "RSI", "ESI", 6),
+            (RDI, "RDI", "EDI", 7),
+        ] {
+            assert_eq!(reg_info(&r), (name, name32, index));
+            assert_eq!(operands_to_str(&Reg(r), &Reg32(RAX)), format!("{name}, EAX"));
+            assert_eq!(operands_to_str(&Reg32(RAX), &Reg32(r)), format!("EAX, {name32}"));
+            assert_eq!(val_to_str(&RegOffset(r, 8)), format!("QWORD [{name} - 8]"));
+            // mov r64, imm32 is REX.W C7 /0, with the register in ModRM.
+            assert_eq!(
+                machine_code(&[Instr::IMov(Reg(r), Imm(1))]),
+                [0x48, 0xc7, 0xc0 + index, 1, 0, 0, 0],
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn run_count_calls_the_code_that_many_times() {
+        let (code, stdout, _) = run_adder(&["--run-count", "3", "-e", "(add1 5)"]);
+        assert_eq!(code, 0);
+        assert!(stdout.contains("Result from long-form code:\n6\n6\n6\nRewritten"), "{stdout}");
+        let (code, stdout, _) = run_adder(&["--run-count", "3", "--quiet", "-e", "(add1 5)"]);
+        assert_eq!((code, stdout.as_str()), (0, "6\n"));
+        assert_eq!(run_adder(&["--run-count", "0", "-e", "5"]).0, EXIT_USAGE_ERROR);
+    }
+
+    #[test]
+    fn e_runs_a_program_from_the_command_line() {
+        for mode in ["--quiet", "--interp"] {
+            let (code, stdout, _) = run_adder(&[mode, "-e", "(add1 (+ 2 3))"]);
+            assert_eq!((code, stdout.as_str()), (0, "6\n"), "{mode}");
+        }
+        let (code, _, stderr) = run_adder(&["-e", "5", "test/add.snek"]);
+        assert_eq!((code, stderr.as_str()), (EXIT_USAGE_ERROR, "cannot use -e together with an input file\n"));
+    }
+
+    #[test]
+    fn interp_skips_codegen_and_agrees_with_it() {
+        let out = env::temp_dir().join(format!("adder-test-{}-interp.s", std::process::id()));
+        let (code, stdout, _) = run_adder(&["--interp", "test/add.snek", out.to_str().unwrap()]);
+        assert_eq!((code, stdout.as_str()), (0, "72\n"));
+        assert!(!out.exists());
+        // One program per ExprKind variant.
+        for source in ["7", "(add1 7)", "(sub1 7)", "(negate 7)", "(mod 7 -3)", "(/ -7 2)", "(+ 7 3)", "(min 7 3)", "(max 7 3)"] {
+            let (interp_result, jit_result) = run_both(source);
+            assert_eq!(interp_result, jit_result, "{source}");
+        }
+    }
+
+    #[test]
+    fn asm_banner_names_the_assembler_and_output() {
+        let out = env::temp_dir().join(format!("adder-test-{}-banner.s", std::process::id()));
+        let out_name = out.to_str().unwrap();
+        assert_eq!(run_adder(&["--asm-banner", "--emit=asm", "test/add.snek", out_name]).0, 0);
+        let asm = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(
+            asm.lines().next(),
+            Some(format!("; NASM syntax, assemble with: nasm -f {NASM_FORMAT} {out_name}").as_str())
+        );
+        assert_eq!(run_adder(&["--emit=asm", "test/add.snek", out_name]).0, 0);
+        assert!(!std::fs::read_to_string(&out).unwrap().contains("assemble with"));
+        std::fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn immediates_are_sized_by_their_other_operand() {
+        for (instr, text) in [
+            (Instr::IMov(Reg(RAX), Imm(5)), "mov RAX, 5"),
+            (Instr::IMov(Reg(RAX), Imm(i32::MIN)), "mov RAX, -2147483648"),
+            (Instr::IMov(Reg(RAX), Imm(i32::MAX)), "mov RAX, 2147483647"),
+            (Instr::ICmp(Reg32(RCX), Imm(-1)), "cmp ECX, -1"),
+            (Instr::IMov(RegOffset(RSP, 8), Imm(7)), "mov QWORD [RSP - 8], 7"),
+            (Instr::IMov(Reg(RCX), RegOffset(RSP, 16)), "mov RCX, QWORD [RSP - 16]"),
+            (Instr::IMovsxd(Reg(RAX), Reg32(RAX)), "movsxd RAX, EAX"),
+        ] {
+            assert_eq!(instr_to_str(&instr), text);
+        }
+        // The JIT's sign-extended imm32 gives the same value the text does.
+        assert_eq!(run_both("-2147483648"), (i32::MIN.into(), i32::MIN.into()));
+    }
+
+    #[test]
+    fn jit_buffer_is_executable_but_not_writable() {
+        let mut jitted = JitFunction::new(&[Instr::IMov(Reg(RAX), Imm(5)), Instr::IRet]).unwrap();
+        let not_writable = |p: Option<String>| p.is_none_or(|p| p.contains('x') && !p.contains('w'));
+        if cfg!(target_os = "linux") {
+            assert_eq!(jitted.protection().as_deref(), Some("r-xp"));
+        }
+        assert!(not_writable(jitted.protection()));
+        jitted.alter(|modifier| {
+            dynasm!(modifier ; .arch x64 ; mov rax, 6 ; ret);
+        });
+        assert!(not_writable(jitted.protection()));
+        assert_eq!(jitted.call(), 6);
+    }
+
+    #[test]
+    fn timeout_gives_up_on_code_that_never_returns() {
+        let fast = JitFunction::new(&[Instr::IMov(Reg(RAX), Imm(5)), Instr::IRet]).unwrap();
+        assert_eq!(fast.call_with_timeout(Duration::from_secs(10)), Some(5));
+        // No program can loop yet, so spin by hand. The thread is leaked,
+        // spinning until the test binary exits.
+        let spin = [Instr::ILabel(String::from("spin")), Instr::IJmp(String::from("spin"))];
+        let slow = JitFunction::new(&spin).unwrap();
+        assert_eq!(slow.call_with_timeout(Duration::from_millis(20)), None);
+    }
+
+    #[test]
+    fn emit_instrs_text_writes_only_the_instructions() {
+        let out = env::temp_dir().join(format!("adder-test-{}-instrs.txt", std::process::id()));
+        let out_name = out.to_str().unwrap();
+        assert_eq!(run_adder(&["--emit=instrs-text", "test/annotate.snek", out_name]).0, 0);
+        let expr = parse_ok("(add1 (sub1 5))");
+        let instrs = compile_to_instrs(&expr, &CompilerConfig::default()).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), format!("{}\n", instrs_to_str(&instrs)));
+        assert!(std::fs::read_to_string(&out).unwrap().starts_with("mov RAX, 5\nsub RAX, 1\nadd RAX, 1\n"));
+        std::fs::remove_file(&out).unwrap();
+        for mode in ["--interp", "--trace-interp", "--batch"] {
+            let (code, _, _) = run_adder(&[mode, "--emit=instrs-text", "test/annotate.snek", out_name]);
+            assert_eq!(code, EXIT_USAGE_ERROR, "{mode}");
+            assert!(!out.exists());
+        }
+    }
+
+    #[test]
+    fn batch_errors_give_lines_in_the_whole_file() {
+        let (code, stdout, stderr) = run_adder(&["--batch", "--no-color", "test/batch.snek"]);
+        assert_eq!((code, stdout.as_str()), (EXIT_COMPILE_ERROR, "2\n3\n"));
+        assert_eq!(
+            stderr,
+            "program 2: error: wrong number of arguments: sub1 expects 1, got 0\n4 | (sub1)\n  | ^\n"
+        );
+        let programs = split_batch("5\x0c  (add1 x)\n\n\n(/ 1 0)");
+        assert_eq!(programs, [(1, "5"), (1, "  (add1 x)\n"), (4, "(/ 1 0)")]);
+        let e = parse_ok(programs[2].1);
+        assert_eq!(source_excerpt(programs[2].1, 4, e.span.start, false), "4 | (/ 1 0)\n  | ^\n");
+        let rendered = parse_program(programs[1].1, 1, false).err().unwrap().1;
+        assert_eq!(rendered, "error: unbound identifier x\n1 |   (add1 x)\n  |         ^\n");
+    }
+
+    // (+ 1 (+ 1 ... (+ 1 innermost))), `depth` additions deep.
+    fn nested_plus(depth: usize, innermost: &str) -> String {
+        format!("{}{innermost}{}", "(+ 1 ".repeat(depth), ")".repeat(depth))
+    }
+
+    #[test]
+    fn deep_programs_get_a_frame_past_the_red_zone() {
+        let compile = |source: &str| compile_to_instrs(&parse_ok(source), &CompilerConfig::default()).unwrap();
+        // 16 slots fill the red zone exactly; no frame yet.
+        let fits = compile(&nested_plus(16, "1"));
+        assert_eq!((peak_stack_bytes(&fits), frame_bytes(&fits)), (128, 0));
+        let deep = compile(&nested_plus(17, "1"));
+        assert_eq!((peak_stack_bytes(&deep), frame_bytes(&deep)), (136, 144));
+        assert_eq!(instr_to_str(&deep[0]), "sub RSP, 144");
+        let ret = deep.iter().position(|i| matches!(i, Instr::IRet)).unwrap();
+        assert_eq!(instr_to_str(&deep[ret - 1]), "add RSP, 144");
+        // Every slot is now at or above RSP.
+        let text = instrs_to_str(&deep);
+        assert!(text.contains("mov QWORD [RSP + 136], RAX") && text.contains("mov QWORD [RSP + 8], RAX"));
+        assert!(!text.contains("[RSP - "));
+        assert_eq!(run_both(&nested_plus(17, "1")), (18, 18));
+        assert_eq!(run_both(&nested_plus(40, "(/ 7 2)")), (43, 43));
+        // snek_error is still called with an aligned stack from inside one.
+        let (code, _, stderr) = run_adder(&["--quiet", "--no-color", "-e", &nested_plus(40, "(/ 1 0)")]);
+        assert_eq!((code, stderr.lines().next()), (EXIT_RUNTIME_ERROR, Some("an error occurred: divide by zero")));
+    }
+
+    #[test]
+    fn temp_dirs_are_removed_unless_kept() {
+        let name = format!("adder-test-{}-temps", std::process::id());
+        let temp = TempDir::new(&name, false).unwrap();
+        let path = temp.path.clone();
+        std::fs::write(path.join("our_code.s"), "ret").unwrap();
+        drop(temp);
+        assert!(!path.exists());
+        drop(TempDir::new(&name, true).unwrap());
+        assert!(path.exists());
+        std::fs::remove_dir_all(&path).unwrap();
+        // A failing step still cleans up: this .s doesn't assemble.
+        let verify_dir = env::temp_dir().join(format!("adder-verify-{}", std::process::id()));
+        match run_text_asm("this is not assembly", false) {
+            Ok(None) => {}
+            result => assert!(result.is_err(), "{result:?}"),
+        }
+        assert!(!verify_dir.exists());
+    }
+
+    // Without nasm this only checks that --verify skips cleanly; the
+    // ignored test below is the one that builds the .s.
+    #[test]
+    fn verify_skips_or_matches_the_jit() {
+        let (code, _, stderr) = run_adder(&["--verify", "--quiet", "-e", "(add1 5)"]);
+        assert_eq!(code, 0, "{stderr}");
+        let skipped = stderr == "--verify skipped: nasm is not installed\n";
+        assert!(skipped || stderr.is_empty(), "{stderr}");
+    }
+
+    #[test]
+    #[ignore = "needs nasm, ar and rustc on PATH"]
+    fn verify_builds_and_runs_the_text_assembly() {
+        for source in ["(add1 5)", "(+ 1 (min 2 -3))", "(mod -2147483648 -1)"] {
+            // A mismatch would exit EXIT_VERIFY_FAILED.
+            let (code, _, stderr) = run_adder(&["--verify", "--quiet", "-e", source]);
+            assert_eq!((code, stderr.as_str()), (0, ""), "{source}");
+        }
+        let (code, _, stderr) = run_adder(&["--verify", "--quiet", "--keep-temps", "-e", "(add1 5)"]);
+        assert_eq!(code, 0, "{stderr}");
+        let dir = stderr.trim().strip_prefix("kept intermediate files in ").unwrap();
+        assert!(std::path::Path::new(dir).join("start.rs").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn batch_scales_to_thousands_of_programs() {
+        let n = 5000;
+        let mut source: String = (0..n).map(|i| format!("(add1 {i})\n\n")).collect();
+        source.push_str("(add1 x)\n");
+        let programs = split_batch(&source);
+        assert_eq!(programs.len(), n + 1);
+        assert_eq!(programs[n - 1], (2 * n - 1, "(add1 4999)\n"));
+
+        let input = env::temp_dir().join(format!("adder-test-{}-batch.snek", std::process::id()));
+        std::fs::write(&input, &source).unwrap();
+        let start = Instant::now();
+        let (code, stdout, stderr) = run_adder(&["--batch", "--no-color", input.to_str().unwrap()]);
+        std::fs::remove_file(&input).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(30), "{:?}", start.elapsed());
+        assert_eq!(code, EXIT_COMPILE_ERROR);
+        assert_eq!(stdout.lines().count(), n);
+        assert_eq!(stdout.lines().last(), Some("5000"));
+        assert_eq!(stderr, "program 5001: error: unbound identifier x\n10001 | (add1 x)\n      |       ^\n");
+    }
+
+    #[test]
+    fn unknown_options_are_usage_errors() {
+        for args in [&["test/add.snek", "--quite"][..], &["--interpp", "test/add.snek"], &["-x", "-e", "5"]] {
+            let (code, stdout, stderr) = run_adder(args);
+            let unknown = args.iter().find(|a| a.starts_with('-') && **a != "-e").unwrap();
+            assert_eq!((code, stdout.as_str()), (EXIT_USAGE_ERROR, ""), "{args:?}");
+            assert_eq!(stderr, format!("unknown option {unknown}\n"));
+        }
+        assert!(!std::path::Path::new("--quite").exists());
+    }
+
+    #[test]
+    fn at_most_two_positional_arguments() {
+        let usage = "usage: adder [options] <input.snek> [output.s]\n";
+        for args in [&[][..], &["test/add.snek", "o1.s", "o2.s"]] {
+            let (code, _, stderr) = run_adder(args);
+            assert_eq!((code, stderr.as_str()), (EXIT_USAGE_ERROR, usage), "{args:?}");
+        }
+        assert!(!std::path::Path::new("o1.s").exists());
+    }
+
+    // Golden files live in test/golden. A test compares its output against
+    // one with assert_snapshot and fails on any difference; run the tests
+    // with UPDATE_SNAPSHOTS=1 to write the current output over the golden
+    // files instead (creating any that are missing) after an intentional
+    // codegen change, then review the diff before committing it.
+    fn check_snapshot(path: &std::path::Path, actual: &str, update: bool) -> Result<(), String> {
+        if update {
+            std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| e.to_string())?;
+            return std::fs::write(path, actual).map_err(|e| e.to_string());
+        }
+        match std::fs::read_to_string(path) {
+            Ok(expected) if expected == actual => Ok(()),
+            Ok(expected) => Err(format!(
+                "{} differs (rerun with UPDATE_SNAPSHOTS=1 to accept)\n--- expected\n{expected}\n--- actual\n{actual}",
+                path.display()
+            )),
+            Err(e) => Err(format!("{}: {e} (run with UPDATE_SNAPSHOTS=1 to create it)", path.display())),
+        }
+    }
+
+    fn assert_snapshot(name: &str, actual: &str) {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test/golden").join(name);
+        let update = env::var_os("UPDATE_SNAPSHOTS").is_some_and(|v| v == "1");
+        if let Err(e) = check_snapshot(&path, actual, update) {
+            panic!("{e}");
+        }
+    }
+
+    #[test]
+    fn snapshots_are_compared_or_updated() {
+        let dir = env::temp_dir().join(format!("adder-test-{}-golden", std::process::id()));
+        let path = dir.join("new.s");
+        assert!(check_snapshot(&path, "ret\n", false).unwrap_err().contains("UPDATE_SNAPSHOTS=1"));
+        check_snapshot(&path, "ret\n", true).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "ret\n");
+        check_snapshot(&path, "ret\n", false).unwrap();
+        assert!(check_snapshot(&path, "nop\n", false).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // The goldens are the ELF listings; Mach-O calls snek_error without the
+    // PLT suffix.
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn listings_match_their_golden_files() {
+        for name in ["add", "annotate", "div_by_zero_nested", "max", "plus_four", "stack_nested"] {
+            let source = std::fs::read_to_string(format!("test/{name}.snek")).unwrap();
+            let expr = parse_ok(&source);
+            let (instrs, origins) = compile_to_instrs_with_origins(&expr, &CompilerConfig::default()).unwrap();
+            let listing = annotated_instrs_to_str(&source, &instrs, &origins);
+            assert_snapshot(&format!("{name}.s"), &format!("{listing}\n"));
+        }
+    }
+
+    #[test]
+    fn diagnose_reports_every_stage() {
+        let (code, stdout, _) = run_adder(&["--diagnose", "-e", "(add1 5)"]);
+        assert_eq!(code, 0);
+        let sections: Vec<&str> = stdout.lines().filter(|l| l.starts_with("=== ")).collect();
+        assert_eq!(
+            sections,
+            ["=== input ===", "=== ast ===", "=== instructions ===", "=== assembly ===", "=== protection ===", "=== result ==="]
+        );
+        assert!(stdout.starts_with("=== input ===\n(add1 5)\n=== ast ===\nExpr {"));
+        assert!(stdout.contains("=== instructions ===\nIMov(Reg(RAX), Imm(5))\nIAdd(Reg(RAX), Imm(1))\n"));
+        assert!(stdout.contains("our_code_starts_here:\n  mov RAX, 5\nadd RAX, 1\n"));
+        assert!(stdout.ends_with("=== result ===\n6\n"), "{stdout}");
+    }
+
+    #[test]
+    fn result_as_exit_code_truncates_to_the_low_byte() {
+        for mode in ["--quiet", "--interp"] {
+            let (code, stdout, _) = run_adder(&[mode, "--result-as-exit-code", "test/seven.snek"]);
+            assert_eq!((code, stdout.as_str()), (7, "7\n"), "{mode}");
+            for (source, expected) in [("-1", 255), ("256", 0), ("(+ 255 2)", 1)] {
+                assert_eq!(run_adder(&[mode, "--result-as-exit-code", "-e", source]).0, expected, "{source} {mode}");
+            }
+        }
+        assert_eq!((result_exit_code(-1), result_exit_code(256), result_exit_code(7)), (255, 0, 7));
+    }
+
+    #[test]
+    fn runtime_errors_point_at_the_failing_expression() {
+        for mode in ["--quiet", "--interp"] {
+            let (code, _, stderr) = run_adder(&[mode, "--no-color", "test/div_by_zero_nested.snek"]);
+            assert_eq!(
+                (code, stderr.as_str()),
+                (EXIT_RUNTIME_ERROR, "an error occurred: divide by zero\n2 | (add1 (mod 5 (/ 1 0)))\n  |              ^\n"),
+                "{mode}"
+            );
+            let (code, _, stderr) = run_adder(&[mode, "--no-color", "-e", "(+ 1\n  (/ 2 (/ -2147483648 -1)))"]);
+            assert_eq!(
+                (code, stderr.as_str()),
+                (EXIT_RUNTIME_ERROR, "an error occurred: overflow\n2 |   (/ 2 (/ -2147483648 -1)))\n  |        ^\n"),
+                "{mode}"
+            );
+        }
+    }
+
+    // Writes `source` to a file and runs --emit=asm on it, returning the .s
+    // path and contents.
+    fn emitted_asm(name: &str, source: &str) -> (std::path::PathBuf, String) {
+        let out = env::temp_dir().join(format!("adder-test-{}-{name}.s", std::process::id()));
+        let input = out.with_extension("snek");
+        std::fs::write(&input, source).unwrap();
+        assert_eq!(run_adder(&["--emit=asm", input.to_str().unwrap(), out.to_str().unwrap()]).0, 0);
+        std::fs::remove_file(&input).unwrap();
+        let asm = std::fs::read_to_string(&out).unwrap();
+        (out, asm)
+    }
+
+    #[test]
+    fn runtime_calls_are_position_independent() {
+        if cfg!(target_os = "macos") {
+            assert_eq!(instr_to_str(&Instr::ICall(String::from("snek_error"))), "call snek_error");
+        } else {
+            assert_eq!(instr_to_str(&Instr::ICall(String::from("snek_error"))), "call snek_error wrt ..plt");
+        }
+        let (out, asm) = emitted_asm("pic", "(/ 1 0)");
+        std::fs::remove_file(&out).unwrap();
+        assert!(asm.starts_with("\ndefault rel\nsection .text\nextern snek_error\n"), "{asm}");
+        assert!(asm.contains(&format!("call snek_error{CALL_SUFFIX}\n")));
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    #[ignore = "needs nasm and readelf on PATH"]
+    fn object_calls_snek_error_through_the_plt() {
+        let (out, _) = emitted_asm("plt", "(/ 1 0)");
+        let obj = out.with_extension("o");
+        run_tool(Command::new("nasm").args(["-f", NASM_FORMAT]).arg(&out).arg("-o").arg(&obj)).unwrap();
+        let relocs = Command::new("readelf").arg("-r").arg(&obj).output().unwrap();
+        std::fs::remove_file(&out).unwrap();
+        std::fs::remove_file(&obj).unwrap();
+        let relocs = String::from_utf8_lossy(&relocs.stdout);
+        assert!(
+            relocs.lines().any(|l| l.contains("R_X86_64_PLT32") && l.contains("snek_error")),
+            "{relocs}"
+        );
+    }
+
+    #[test]
+    fn result_is_sign_or_zero_extended_to_64_bits() {
+        let expr = parse_ok("(sub1 0)");
+        let call = |result_extension| {
+            let config = CompilerConfig { result_extension, ..Default::default() };
+            JitFunction::new(&compile_to_instrs(&expr, &config).unwrap()).unwrap().call()
+        };
+        assert_eq!(call(ResultExtension::Sign), -1);
+        assert_eq!(call(ResultExtension::Zero), 4294967295);
+        let (code, stdout, _) = run_adder(&["--zero-extend", "--quiet", "-e", "(sub1 0)"]);
+        assert_eq!((code, stdout.as_str()), (0, "4294967295\n"));
+    }
+
+    #[test]
+    fn interp_stops_when_the_step_budget_runs_out() {
+        let expr = parse_ok(&std::fs::read_to_string("test/steps.snek").unwrap());
+        assert_eq!(interp(&expr, &mut Some(4)).ok(), Some(3));
+        let mut budget = Some(10);
+        assert_eq!(interp(&expr, &mut budget).ok(), Some(3));
+        assert_eq!(budget, Some(6));
+        let Err((e, span)) = interp(&expr, &mut Some(3)) else { panic!("finished within 3 steps") };
+        assert!(matches!(e, InterpError::StepLimitExceeded));
+        // The step that didn't fit is the innermost 0.
+        assert_eq!(span.len(), 1);
+        assert_eq!(interp(&expr, &mut None).ok(), Some(3));
+        let (code, _, stderr) = run_adder(&["--interp", "--max-steps", "3", "--no-color", "test/steps.snek"]);
+        assert_eq!((code, stderr.lines().next()), (EXIT_RUNTIME_ERROR, Some("an error occurred: step limit exceeded")));
+    }
+
+    #[test]
+    fn compare_modes_prints_the_costs_and_a_verdict() {
+        let (code, stdout, _) = run_adder(&["--compare-modes", "10", "test/deep.snek"]);
+        assert_eq!(code, 0);
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(lines.len(), 4, "{stdout}");
+        for (line, label) in lines.iter().zip(["compile cost:", "JIT cost per call:", "interp cost per call:"]) {
+            assert!(line.starts_with(label), "{line}");
+        }
+        let verdict = lines[3];
+        let wins = verdict.strip_prefix("JIT wins after ").and_then(|r| r.strip_suffix(" runs"));
+        assert!(
+            wins.is_some_and(|n| n.parse::<u128>().is_ok()) || verdict == "JIT never wins: interp is at least as fast per call",
+            "{verdict}"
+        );
+        assert_eq!(run_adder(&["--compare-modes", "0", "test/deep.snek"]).0, EXIT_USAGE_ERROR);
+    }
+
+    #[test]
+    fn char_literals_read_as_their_code_points() {
+        assert_eq!(run_both("#\\A"), (65, 65));
+        assert_eq!(run_both("(add1 #\\newline)"), (11, 11));
+        let source = "#\\bogus";
+        let error = parse_error(source);
+        assert!(matches!(error, ParseError::UnknownChar(ref c) if c == "bogus"), "{error:?}");
+        assert_eq!(parse_err(source), "error: unknown character literal #\\bogus\n1 | #\\bogus\n  | ^\n");
+    }
+
+    #[test]
+    fn variadic_operators_fold_every_operand() {
+        assert_eq!(run_both("(+ 1 2 3 4)"), (10, 10));
+        assert_eq!(run_both("(min 5 -3 7 2)"), (-3, -3));
+        assert_eq!(
+            parse_err("(+ 5)"),
+            "error: wrong number of arguments: + expects at least 2, got 1\n1 | (+ 5)\n  | ^\n"
+        );
+    }
+
+    #[test]
+    fn empty_and_misapplied_forms_are_explained() {
+        assert_eq!(parse_err("()"), "error: empty expression: () is not a valid expression\n1 | ()\n  | ^\n");
+        assert_eq!(
+            parse_err("(add1)"),
+            "error: wrong number of arguments: add1 expects 1, got 0\n1 | (add1)\n  | ^\n"
+        );
+        assert_eq!(
+            parse_err("(add1 1 2)"),
+            "error: wrong number of arguments: add1 expects 1, got 2\n1 | (add1 1 2)\n  | ^\n"
+        );
+    }
+
+    // The ParseError itself, for tests that care about its fields rather
+    // than how it's rendered.
+    fn parse_error(source: &str) -> ParseError {
+        parse_expr(&sexp::parse(source).unwrap(), &scan_spans(source, &mut 0)).err().unwrap().0
+    }
+
+    #[test]
+    fn wrong_arity_records_what_was_expected_and_given() {
+        let error = parse_error("(sub1)");
+        assert!(matches!(error, ParseError::WrongArity { ref op, expected: 1, got: 0 } if op == "sub1"), "{error:?}");
+        let error = parse_error("(negate 1 2)");
+        assert!(matches!(error, ParseError::WrongArity { ref op, expected: 1, got: 2 } if op == "negate"), "{error:?}");
+    }
+
+    #[test]
+    fn malformed_sexps_point_where_reading_stopped() {
+        let read = |name| parse_err(&std::fs::read_to_string(name).unwrap());
+        assert_eq!(
+            read("test/unbalanced_open.snek"),
+            "error: malformed s-expression: unexpected eof\n1 | (((((((((\n  |          ^\n"
+        );
+        assert_eq!(
+            read("test/unbalanced_close.snek"),
+            "error: malformed s-expression: unrecognized post-s-expression data\n1 | (add1 5))\n  |         ^\n"
+        );
+        assert_eq!(
+            read("test/stray_atom.snek"),
+            "error: malformed s-expression: unrecognized post-s-expression data\n2 | 5 6\n  |   ^\n"
+        );
+    }
+
+    #[test]
+    fn id_and_begin_leave_no_node_behind() {
+        let expr = parse_ok("(begin (id (sub1 (begin 3))))");
+        let ExprKind::Sub1(ref operand) = expr.kind else { panic!("{expr:?}") };
+        assert!(matches!(operand.kind, ExprKind::Num(3)), "{expr:?}");
+        assert_eq!(run_both("(begin (id (sub1 (begin 3))))"), (2, 2));
+    }
+}
@@ -0,0 +1,15 @@
+mov RAX, 73                     ; from: 73
+add RAX, 1                      ; from: (add1 73)
+sub RAX, 1                      ; from: (sub1 (add1 73))
+sub RAX, 1                      ; from: (sub1 (sub1 (add1 73)))
+movsxd RAX, EAX
+ret
+divide_by_zero:
+mov RDI, 1
+jmp throw_error
+overflow:
+mov RDI, 2
+jmp throw_error
+throw_error:
+sub RSP, 8
+call snek_error wrt ..plt
@@ -0,0 +1,14 @@
+mov RAX, 5                      ; from: 5
+sub RAX, 1                      ; from: (sub1 5)
+add RAX, 1                      ; from: (add1 (sub1 5))
+movsxd RAX, EAX
+ret
+divide_by_zero:
+mov RDI, 1
+jmp throw_error
+overflow:
+mov RDI, 2
+jmp throw_error
+throw_error:
+sub RSP, 8
+call snek_error wrt ..plt
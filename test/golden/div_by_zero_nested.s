@@ -0,0 +1,43 @@
+mov RAX, 5                      ; from: 5
+mov QWORD [RSP - 8], RAX        ; from: (mod 5 (/ 1 0))
+mov RAX, 1                      ; from: 1
+mov QWORD [RSP - 16], RAX       ; from: (/ 1 0)
+mov RAX, 0                      ; from: 0
+mov RCX, RAX                    ; from: (/ 1 0)
+mov RAX, QWORD [RSP - 16]       ; from: (/ 1 0)
+mov RSI, 3                      ; from: (/ 1 0)
+cmp ECX, 0                      ; from: (/ 1 0)
+je divide_by_zero               ; from: (/ 1 0)
+cmp ECX, -1                     ; from: (/ 1 0)
+jne divide_1                    ; from: (/ 1 0)
+cmp EAX, -2147483648            ; from: (/ 1 0)
+je overflow                     ; from: (/ 1 0)
+divide_1:                       ; from: (/ 1 0)
+cdq                             ; from: (/ 1 0)
+idiv ECX                        ; from: (/ 1 0)
+mov RCX, RAX                    ; from: (mod 5 (/ 1 0))
+mov RAX, QWORD [RSP - 8]        ; from: (mod 5 (/ 1 0))
+mov RSI, 1                      ; from: (mod 5 (/ 1 0))
+cmp ECX, 0                      ; from: (mod 5 (/ 1 0))
+je divide_by_zero               ; from: (mod 5 (/ 1 0))
+cmp ECX, -1                     ; from: (mod 5 (/ 1 0))
+jne divide_0                    ; from: (mod 5 (/ 1 0))
+mov RAX, 0                      ; from: (mod 5 (/ 1 0))
+jmp divide_done_2               ; from: (mod 5 (/ 1 0))
+divide_0:                       ; from: (mod 5 (/ 1 0))
+cdq                             ; from: (mod 5 (/ 1 0))
+idiv ECX                        ; from: (mod 5 (/ 1 0))
+mov RAX, RDX                    ; from: (mod 5 (/ 1 0))
+divide_done_2:                  ; from: (mod 5 (/ 1 0))
+add RAX, 1                      ; from: (add1 (mod 5 (/ 1 0)))
+movsxd RAX, EAX
+ret
+divide_by_zero:
+mov RDI, 1
+jmp throw_error
+overflow:
+mov RDI, 2
+jmp throw_error
+throw_error:
+sub RSP, 8
+call snek_error wrt ..plt
@@ -0,0 +1,12 @@
+mov RAX, 2147483647             ; from: 2147483647
+movsxd RAX, EAX
+ret
+divide_by_zero:
+mov RDI, 1
+jmp throw_error
+overflow:
+mov RDI, 2
+jmp throw_error
+throw_error:
+sub RSP, 8
+call snek_error wrt ..plt
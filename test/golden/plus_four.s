@@ -0,0 +1,24 @@
+mov RAX, 1                      ; from: 1
+mov QWORD [RSP - 8], RAX        ; from: (+ 1 2 3 4)
+mov RAX, 2                      ; from: 2
+mov RCX, QWORD [RSP - 8]        ; from: (+ 1 2 3 4)
+add RAX, RCX                    ; from: (+ 1 2 3 4)
+mov QWORD [RSP - 8], RAX        ; from: (+ 1 2 3 4)
+mov RAX, 3                      ; from: 3
+mov RCX, QWORD [RSP - 8]        ; from: (+ 1 2 3 4)
+add RAX, RCX                    ; from: (+ 1 2 3 4)
+mov QWORD [RSP - 8], RAX        ; from: (+ 1 2 3 4)
+mov RAX, 4                      ; from: 4
+mov RCX, QWORD [RSP - 8]        ; from: (+ 1 2 3 4)
+add RAX, RCX                    ; from: (+ 1 2 3 4)
+movsxd RAX, EAX
+ret
+divide_by_zero:
+mov RDI, 1
+jmp throw_error
+overflow:
+mov RDI, 2
+jmp throw_error
+throw_error:
+sub RSP, 8
+call snek_error wrt ..plt